@@ -0,0 +1,275 @@
+use std::error::Error;
+
+use serde::{Serialize, Deserialize};
+
+/// Character appended to a charging battery level.
+const CHAR_CHARGING: char = '\u{26A1}';
+
+/// How full a battery is, for picking its glyph and color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Grade {
+    Empty,
+    Low,
+    Half,
+    Full,
+}
+
+impl Grade {
+    pub fn of(percentage: u8) -> Self {
+        match percentage {
+            0..=10 => Grade::Empty,
+            11..=30 => Grade::Low,
+            31..=70 => Grade::Half,
+            _ => Grade::Full,
+        }
+    }
+}
+
+/// Glyphs in front of the battery levels, per grade plus one for charging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatteryIcons {
+    empty: String,
+    low: String,
+    half: String,
+    full: String,
+    charging: String,
+}
+
+impl BatteryIcons {
+    /// `emoji` (default), `nerd` (Nerd Fonts), `fontawesome`, or five
+    /// comma-separated glyphs: empty, low, half, full, charging.
+    pub fn from_config(theme: &str) -> Result<Self, Box<dyn Error>> {
+        let glyphs: Vec<&str> = match theme.trim() {
+            "emoji" | "" => vec!["\u{1FAAB}", "\u{1FAAB}", "\u{1F50B}", "\u{1F50B}", "\u{1F50C}"],
+            "nerd" => vec!["\u{F008E}", "\u{F007B}", "\u{F007E}", "\u{F0079}", "\u{F0084}"],
+            "fontawesome" => vec!["\u{F244}", "\u{F243}", "\u{F242}", "\u{F240}", "\u{F0E7}"],
+            custom => custom.split(',').map(str::trim).collect(),
+        };
+        match glyphs.as_slice() {
+            [empty, low, half, full, charging] => Ok(Self {
+                empty: empty.to_string(),
+                low: low.to_string(),
+                half: half.to_string(),
+                full: full.to_string(),
+                charging: charging.to_string(),
+            }),
+            _ => Err(format!("Invalid BATTERY_ICONS `{}`: expected a theme or five comma-separated glyphs", theme).into()),
+        }
+    }
+
+    fn glyph(&self, level: &Level) -> &str {
+        if level.charging {
+            return &self.charging;
+        }
+        match Grade::of(level.percentage) {
+            Grade::Empty => &self.empty,
+            Grade::Low => &self.low,
+            Grade::Half => &self.half,
+            Grade::Full => &self.full,
+        }
+    }
+}
+
+impl Default for BatteryIcons {
+    fn default() -> Self {
+        Self::from_config("emoji").unwrap()
+    }
+}
+
+/// Which battery of a device a level belongs to. True-wireless earbuds report
+/// one per bud plus the case; everything else has a single `Main` battery.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Part {
+    #[default]
+    Main,
+    Left,
+    Right,
+    Case,
+}
+
+impl Part {
+    /// Guess the part from a battery's name, model or object path, e.g.
+    /// `Galaxy Buds2 Pro (Left)` or `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF/battery_case`.
+    /// Only whole words count, so `Bright` or `Showcase` stay `Main`.
+    pub fn from_name(name: &str) -> Self {
+        let name = name.to_lowercase();
+        let has = |word: &str| name.split(|c: char| !c.is_alphanumeric()).any(|w| w == word);
+        if has("left") {
+            Part::Left
+        } else if has("right") {
+            Part::Right
+        } else if has("case") {
+            Part::Case
+        } else {
+            Part::Main
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Part::Main => "",
+            Part::Left => "L",
+            Part::Right => "R",
+            Part::Case => "C",
+        }
+    }
+}
+
+/// Charge of one battery.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level {
+    pub part: Part,
+    pub percentage: u8,
+    pub charging: bool,
+}
+
+impl Level {
+    /// Labelled level of one part, e.g. `L80` or `C40⚡`.
+    fn render(&self) -> String {
+        let mut out = format!("{}{}", self.part.label(), self.percentage);
+        if self.charging {
+            out.push(CHAR_CHARGING);
+        }
+        out
+    }
+}
+
+/// The batteries of one device, at most one level per [`Part`], ordered by part.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Battery {
+    levels: Vec<Level>,
+}
+
+impl Battery {
+    /// A device with a single battery.
+    pub fn single(percentage: u8, charging: bool) -> Self {
+        Self { levels: vec![Level { part: Part::Main, percentage, charging }] }
+    }
+
+    /// Set the level of `level.part`, replacing any previous one.
+    pub fn set(&mut self, level: Level) {
+        match self.levels.binary_search_by_key(&level.part, |l| l.part) {
+            Ok(i) => self.levels[i] = level,
+            Err(i) => self.levels.insert(i, level),
+        }
+    }
+
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// The emptiest battery in use, used where there's only room for one
+    /// number: the lowest non-charging one, not counting an earbud case, which
+    /// spends most of its time charging or in a bag. Falls back to a charging
+    /// bud, then to the case if nothing else reported.
+    pub fn lowest(&self) -> Option<&Level> {
+        let buds = || self.levels.iter().filter(|l| l.part != Part::Case);
+        buds().filter(|l| !l.charging).min_by_key(|l| l.percentage)
+            .or_else(|| buds().min_by_key(|l| l.percentage))
+            .or_else(|| self.levels.iter().min_by_key(|l| l.percentage))
+    }
+
+    /// Every level, e.g. `🔋80%`, `🔌80%` or `🔋L80 R75 C40⚡`, behind the glyph
+    /// of the [`lowest`](Self::lowest) level. Separate parts mark each charging one with `⚡`.
+    pub fn render(&self, icons: &BatteryIcons) -> String {
+        let glyph = match self.lowest() {
+            Some(l) => icons.glyph(l),
+            None => return String::new(),
+        };
+        match self.levels.as_slice() {
+            [only] if only.part == Part::Main => format!("{}{}%", glyph, only.percentage),
+            levels => {
+                let parts: Vec<String> = levels.iter().map(Level::render).collect();
+                format!("{}{}", glyph, parts.join(" "))
+            }
+        }
+    }
+
+    /// Only the lowest level, e.g. `🔋75%`, for `short_text`.
+    pub fn render_compact(&self, icons: &BatteryIcons) -> String {
+        self.lowest()
+            .map(|l| format!("{}{}%", icons.glyph(l), l.percentage))
+            .unwrap_or_default()
+    }
+}
+
+/// Collects levels; the first level seen for a part wins.
+impl FromIterator<Level> for Battery {
+    fn from_iter<I: IntoIterator<Item = Level>>(iter: I) -> Self {
+        let mut battery = Battery::default();
+        for level in iter {
+            if !battery.levels.iter().any(|l| l.part == level.part) {
+                battery.set(level);
+            }
+        }
+        battery
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn level(part: Part, percentage: u8, charging: bool) -> Level {
+        Level { part, percentage, charging }
+    }
+
+    #[test]
+    fn render_single_and_earbuds() {
+        let icons = BatteryIcons::default();
+        assert_eq!(Battery::single(80, false).render(&icons), "🔋80%");
+        assert_eq!(Battery::single(80, true).render(&icons), "🔌80%");
+        assert_eq!(Battery::single(8, false).render(&icons), "🪫8%");
+        let buds: Battery = [
+            level(Part::Case, 40, true),
+            level(Part::Left, 80, false),
+            level(Part::Right, 75, false),
+        ].into_iter().collect();
+        assert_eq!(buds.render(&icons), "🔋L80 R75 C40⚡");
+        assert_eq!(buds.render_compact(&icons), "🔋75%");
+        let low_case: Battery = [level(Part::Left, 60, true), level(Part::Right, 55, false), level(Part::Case, 5, false)].into_iter().collect();
+        assert_eq!(low_case.render_compact(&icons), "🔋55%");
+        let charging: Battery = [level(Part::Left, 60, true), level(Part::Case, 5, false)].into_iter().collect();
+        assert_eq!(charging.render_compact(&icons), "🔌60%");
+        let only_case: Battery = [level(Part::Case, 15, false)].into_iter().collect();
+        assert_eq!(only_case.render_compact(&icons), "🪫15%");
+        assert_eq!(Battery::default().render(&icons), "");
+    }
+
+    #[test]
+    fn icon_themes_and_custom_glyphs() {
+        let custom = BatteryIcons::from_config("E, L, H, F, C").unwrap();
+        assert_eq!(Battery::single(5, false).render(&custom), "E5%");
+        assert_eq!(Battery::single(25, false).render(&custom), "L25%");
+        assert_eq!(Battery::single(50, false).render(&custom), "H50%");
+        assert_eq!(Battery::single(100, false).render(&custom), "F100%");
+        assert_eq!(Battery::single(100, true).render(&custom), "C100%");
+        assert!(BatteryIcons::from_config("nerd").is_ok());
+        assert!(BatteryIcons::from_config("fontawesome").is_ok());
+        assert!(BatteryIcons::from_config("a,b").is_err());
+    }
+
+    #[test]
+    fn set_replaces_part_and_collect_keeps_first() {
+        let mut b = Battery::single(50, false);
+        b.set(level(Part::Main, 45, false));
+        b.set(level(Part::Left, 90, false));
+        assert_eq!(b.levels(), &[level(Part::Main, 45, false), level(Part::Left, 90, false)]);
+        let first: Battery = [level(Part::Main, 10, false), level(Part::Main, 99, false)].into_iter().collect();
+        assert_eq!(first.levels(), &[level(Part::Main, 10, false)]);
+    }
+
+    #[test]
+    fn part_from_name() {
+        assert_eq!(Part::from_name("Galaxy Buds2 Pro (Left)"), Part::Left);
+        assert_eq!(Part::from_name("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF/battery_case"), Part::Case);
+        assert_eq!(Part::from_name("WH-1000XM4"), Part::Main);
+        assert_eq!(Part::from_name("right-bud"), Part::Right);
+        assert_eq!(Part::from_name("Bright Buds"), Part::Main);
+        assert_eq!(Part::from_name("Showcase Leftover"), Part::Main);
+    }
+}
@@ -0,0 +1,269 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use zbus::blocking::{fdo::{DBusProxy, ObjectManagerProxy}, Connection, MessageIterator, Proxy};
+use zbus::{MatchRule, MessageType};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
+
+use super::system_bus;
+use super::battery::Part;
+
+/// Interfaces and their properties of one D-Bus object, keyed by interface name.
+type Interfaces = HashMap<String, HashMap<String, OwnedValue>>;
+
+lazy_static! {
+    /// BlueZ devices by MAC, from `GetManagedObjects`. `None` until fetched, and
+    /// again after BlueZ reports added or removed interfaces.
+    static ref DEVICES: Mutex<Option<HashMap<String, BluezDevice>>> = Mutex::new(None);
+}
+
+/// A BlueZ device object.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BluezDevice {
+    /// Example: `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF`
+    pub path: String,
+    /// Example: `/org/bluez/hci0`
+    pub adapter: String,
+    /// Object paths with `org.bluez.Battery1`: the device itself and, for
+    /// earbuds with a vendor battery provider, its per-bud/case children.
+    pub batteries: Vec<String>,
+}
+
+/// Look up the BlueZ device for a MAC, fetching all devices with one
+/// `GetManagedObjects` call when the cache is empty.
+pub fn find_device(conn: &Connection, mac: &str) -> Option<BluezDevice> {
+    let mut guard = DEVICES.lock().unwrap();
+    if guard.is_none() {
+        let objects = ObjectManagerProxy::builder(conn)
+            .destination("org.bluez").ok()?
+            .path("/").ok()?
+            .build().ok()?
+            .get_managed_objects().ok()?;
+        *guard = Some(index_devices(objects.into_iter().map(|(path, ifaces)| {
+            (path.to_string(), ifaces.into_iter().map(|(i, props)| (i.to_string(), props)).collect())
+        })));
+    }
+    guard.as_ref()?.get(&mac.to_uppercase()).cloned()
+}
+
+/// Forget the cached devices; the next lookup fetches them again.
+pub fn invalidate_devices() {
+    *DEVICES.lock().unwrap() = None;
+}
+
+/// Connect the paired device `mac` if it's disconnected, else disconnect it.
+/// `on_start` is told which (`true` = connecting) before the call, which can
+/// take several seconds. Returns whether the device is connected afterwards.
+pub fn toggle_connection(conn: &Connection, mac: &str, on_start: impl FnOnce(bool)) -> zbus::Result<bool> {
+    let device = find_device(conn, mac).ok_or_else(|| zbus::Error::Failure("not paired".into()))?;
+    let proxy = Proxy::new(conn, "org.bluez", device.path.as_str(), "org.bluez.Device1")?;
+    let connect = !proxy.get_property::<bool>("Connected")?;
+    on_start(connect);
+    let () = proxy.call(if connect { "Connect" } else { "Disconnect" }, &())?;
+    Ok(connect)
+}
+
+/// Short, user-facing text for a failed BlueZ call, e.g. `br-connection-page-timeout`
+/// for an `org.bluez.Error.Failed` or `NotReady` for an `org.bluez.Error.NotReady`.
+pub fn error_text(e: &zbus::Error) -> String {
+    match e {
+        zbus::Error::MethodError(name, detail, _) => detail.clone()
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| name.rsplit('.').next().unwrap_or_default().to_string()),
+        zbus::Error::Failure(msg) => msg.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Index the `org.bluez.Device1` objects among `objects` by their MAC, along
+/// with the `org.bluez.Battery1` objects belonging to each.
+fn index_devices(objects: impl IntoIterator<Item = (String, Interfaces)>) -> HashMap<String, BluezDevice> {
+    let mut devices = HashMap::new();
+    let mut batteries = Vec::new();
+    for (path, ifaces) in objects {
+        if ifaces.contains_key("org.bluez.Battery1") {
+            batteries.push(path.clone());
+        }
+        let device = match ifaces.get("org.bluez.Device1") {
+            Some(d) => d,
+            None => continue,
+        };
+        let mac = match device.get("Address").and_then(|v| v.downcast_ref::<str>()) {
+            Some(a) => a.to_uppercase(),
+            None => continue,
+        };
+        let adapter = device.get("Adapter")
+            .and_then(|v| v.downcast_ref::<ObjectPath>())
+            .map(|p| p.to_string())
+            .unwrap_or_default();
+        devices.insert(mac, BluezDevice { path, adapter, batteries: Vec::new() });
+    }
+    batteries.sort();
+    for path in batteries {
+        if let Some(device) = battery_owner(&path).and_then(|(mac, _)| devices.get_mut(&mac)) {
+            device.batteries.push(path);
+        }
+    }
+    devices
+}
+
+/// Something the battery watcher learned from BlueZ.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BluezEvent {
+    /// The level of one of a device's batteries, straight from a signal.
+    Battery { mac: String, part: Part, percentage: u8 },
+    /// A device (re)appeared without battery data; it's worth looking it up now.
+    DeviceAdded { mac: String },
+}
+
+/// Subscribe to `org.bluez.Battery1` property changes and BlueZ `InterfacesAdded`/
+/// `InterfacesRemoved` on the shared system-bus connection, calling `on_event`
+/// for each relevant signal and keeping the device cache current. Blocks for as
+/// long as the connection lives; returns on error.
+pub fn watch_battery_signals(mut on_event: impl FnMut(BluezEvent)) -> zbus::Result<()> {
+    let conn = system_bus().ok_or_else(|| zbus::Error::Failure("no system bus".into()))?;
+    // Start listening before adding the match rules so no signal slips through.
+    let messages = MessageIterator::from(&conn);
+    let dbus = DBusProxy::new(&conn)?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.bluez")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .arg(0, "org.bluez.Battery1")?
+        .build())?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.bluez")?
+        .interface("org.freedesktop.DBus.ObjectManager")?
+        .member("InterfacesAdded")?
+        .build())?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.bluez")?
+        .interface("org.freedesktop.DBus.ObjectManager")?
+        .member("InterfacesRemoved")?
+        .build())?;
+    // Anything missed while we weren't listening.
+    invalidate_devices();
+
+    for msg in messages {
+        let msg = msg?;
+        let (interface, member) = match (msg.interface(), msg.member()) {
+            (Some(i), Some(m)) => (i.to_string(), m.to_string()),
+            _ => continue,
+        };
+        let event = match (interface.as_str(), member.as_str()) {
+            ("org.freedesktop.DBus.Properties", "PropertiesChanged") => {
+                let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+                match msg.body::<(String, HashMap<String, OwnedValue>, Vec<String>)>() {
+                    Ok((iface, changed, _)) if iface == "org.bluez.Battery1" => {
+                        battery_event(&path, changed.get("Percentage"))
+                    }
+                    _ => None,
+                }
+            }
+            ("org.freedesktop.DBus.ObjectManager", "InterfacesRemoved") => {
+                invalidate_devices();
+                None
+            }
+            ("org.freedesktop.DBus.ObjectManager", "InterfacesAdded") => {
+                invalidate_devices();
+                match msg.body::<(OwnedObjectPath, Interfaces)>() {
+                    Ok((path, ifaces)) => match ifaces.get("org.bluez.Battery1") {
+                        Some(props) => battery_event(path.as_str(), props.get("Percentage")),
+                        None if ifaces.contains_key("org.bluez.Device1") => {
+                            mac_from_device_path(path.as_str()).map(|mac| BluezEvent::DeviceAdded { mac })
+                        }
+                        None => None,
+                    },
+                    Err(_) => None,
+                }
+            }
+            _ => None,
+        };
+        if let Some(e) = event {
+            on_event(e);
+        }
+    }
+    Ok(())
+}
+
+fn battery_event(path: &str, percentage: Option<&OwnedValue>) -> Option<BluezEvent> {
+    let (mac, part) = battery_owner(path)?;
+    let percentage = *percentage?.downcast_ref::<u8>()?;
+    Some(BluezEvent::Battery { mac, part, percentage })
+}
+
+/// The device MAC and battery part of an `org.bluez.Battery1` object: the
+/// device object itself is its main battery, a child object (from a vendor
+/// battery provider) is named after the part, e.g. `.../dev_AA_BB_CC_DD_EE_FF/battery_left`.
+pub fn battery_owner(path: &str) -> Option<(String, Part)> {
+    if let Some(mac) = mac_from_device_path(path) {
+        return Some((mac, Part::Main));
+    }
+    let (parent, name) = path.rsplit_once('/')?;
+    Some((mac_from_device_path(parent)?, Part::from_name(name)))
+}
+
+/// Extract the MAC from a BlueZ device object path.
+/// Example: `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF` -> `AA:BB:CC:DD:EE:FF`
+pub fn mac_from_device_path(path: &str) -> Option<String> {
+    let dev = path.rsplit('/').next()?.strip_prefix("dev_")?;
+    let parts: Vec<&str> = dev.split('_').collect();
+    if parts.len() != 6 || !parts.iter().all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit())) {
+        return None;
+    }
+    Some(parts.join(":").to_uppercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn props(entries: Vec<(&str, OwnedValue)>) -> HashMap<String, OwnedValue> {
+        entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+    }
+
+    #[test]
+    fn index_devices_by_address() {
+        let headset: Interfaces = [
+            ("org.bluez.Device1".to_string(), props(vec![
+                ("Address", OwnedValue::from(zbus::zvariant::Str::from("aa:bb:cc:dd:ee:ff"))),
+                ("Adapter", OwnedValue::from(ObjectPath::try_from("/org/bluez/usb0").unwrap())),
+            ])),
+            ("org.bluez.Battery1".to_string(), props(vec![("Percentage", OwnedValue::from(80u8))])),
+        ].into_iter().collect();
+        let adapter: Interfaces = [("org.bluez.Adapter1".to_string(), HashMap::new())].into_iter().collect();
+        let left_bud: Interfaces = [("org.bluez.Battery1".to_string(), props(vec![("Percentage", OwnedValue::from(70u8))]))].into_iter().collect();
+        let devices = index_devices(vec![
+            ("/org/bluez/usb0/dev_AA_BB_CC_DD_EE_FF/battery_left".to_string(), left_bud),
+            ("/org/bluez/usb0/dev_AA_BB_CC_DD_EE_FF".to_string(), headset),
+            ("/org/bluez/usb0".to_string(), adapter),
+        ]);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["AA:BB:CC:DD:EE:FF"], BluezDevice {
+            path: "/org/bluez/usb0/dev_AA_BB_CC_DD_EE_FF".into(),
+            adapter: "/org/bluez/usb0".into(),
+            batteries: vec!["/org/bluez/usb0/dev_AA_BB_CC_DD_EE_FF".into(), "/org/bluez/usb0/dev_AA_BB_CC_DD_EE_FF/battery_left".into()],
+        });
+    }
+
+    #[test]
+    fn mac_from_device_path_parses() {
+        assert_eq!(mac_from_device_path("/org/bluez/hci0/dev_aa_BB_CC_DD_EE_FF"), Some("AA:BB:CC:DD:EE:FF".to_string()));
+        assert_eq!(mac_from_device_path("/org/bluez/hci0"), None);
+        assert_eq!(mac_from_device_path("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF/sep1"), None);
+    }
+
+    #[test]
+    fn battery_event_needs_percentage() {
+        let path = "/org/bluez/hci1/dev_00_1A_7D_DA_71_13";
+        let pct = OwnedValue::from(42u8);
+        assert_eq!(battery_event(path, Some(&pct)), Some(BluezEvent::Battery { mac: "00:1A:7D:DA:71:13".into(), part: Part::Main, percentage: 42 }));
+        assert_eq!(battery_event(&format!("{}/battery_case", path), Some(&pct)),
+            Some(BluezEvent::Battery { mac: "00:1A:7D:DA:71:13".into(), part: Part::Case, percentage: 42 }));
+        assert_eq!(battery_event(path, None), None);
+        assert_eq!(battery_event(path, Some(&OwnedValue::from(42u32))), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::{Duration, Instant};
+
+use libpulse_binding::volume::{ChannelVolumes, Volume};
+
+/// Shortest gap between two fade steps.
+const MIN_STEP_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest gap between two fade steps (keeps long sleep-timer fades smooth).
+const MAX_STEP_INTERVAL: Duration = Duration::from_secs(5);
+/// Target number of steps for a single fade.
+const FADE_STEPS: u32 = 50;
+
+/// What a fade is for, which decides what happens once it completes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeKind {
+    /// A plain volume ramp (scroll wheel).
+    Volume,
+    /// Ramp down, then mute and put the original volume back.
+    Mute,
+    /// Ramp up from silence after unmuting.
+    Unmute,
+    /// Sleep timer: a long ramp down, then mute and put the original volume back.
+    Sleep,
+}
+
+/// A volume ramp on a single sink, advanced by the event loop's fade timer.
+#[derive(Clone)]
+pub struct Fade {
+    pub kind: FadeKind,
+    pub sink_name: String,
+    pub from: ChannelVolumes,
+    pub to: ChannelVolumes,
+    pub start: Instant,
+    pub duration: Duration,
+}
+
+impl Fade {
+    pub fn new(kind: FadeKind, sink_name: String, from: ChannelVolumes, to: ChannelVolumes, duration: Duration) -> Self {
+        Self { kind, sink_name, from, to, start: Instant::now(), duration }
+    }
+
+    /// Fraction of the ramp completed at `now`, in `0.0..=1.0`.
+    pub fn progress(&self, now: Instant) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (now.saturating_duration_since(self.start).as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+    }
+
+    /// Volume the sink should be at `now`.
+    pub fn volume_at(&self, now: Instant) -> ChannelVolumes {
+        interpolate(&self.from, &self.to, self.progress(now))
+    }
+
+    /// Time left until the ramp completes.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        (self.start + self.duration).saturating_duration_since(now)
+    }
+
+    /// Whether the original volume must be restored after muting at the end.
+    pub fn mutes_at_end(&self) -> bool {
+        matches!(self.kind, FadeKind::Mute | FadeKind::Sleep)
+    }
+
+    /// This ramp restarted from `level` at `now`, heading for silence over the
+    /// time it had left, for a sleep timer the volume was changed under.
+    pub fn restarted_from(&self, level: ChannelVolumes, now: Instant) -> Self {
+        Self { from: level, to: silenced(&level), start: now, duration: self.remaining(now), ..self.clone() }
+    }
+
+    /// Delay between two steps of this fade.
+    pub fn step_interval(&self) -> Duration {
+        (self.duration / FADE_STEPS).clamp(MIN_STEP_INTERVAL, MAX_STEP_INTERVAL)
+    }
+}
+
+/// Linear per-channel interpolation between two volumes. Channels missing from
+/// `to` keep their `from` value.
+pub fn interpolate(from: &ChannelVolumes, to: &ChannelVolumes, t: f64) -> ChannelVolumes {
+    let t = t.clamp(0.0, 1.0);
+    let mut out = *from;
+    for (i, v) in out.get_mut().iter_mut().enumerate() {
+        if let Some(target) = to.get().get(i) {
+            let a = v.0 as f64;
+            let b = target.0 as f64;
+            *v = Volume((a + (b - a) * t).round() as u32);
+        }
+    }
+    out
+}
+
+/// `cv` with every channel set to silence, keeping the channel count.
+pub fn silenced(cv: &ChannelVolumes) -> ChannelVolumes {
+    let mut out = *cv;
+    for v in out.get_mut() {
+        *v = Volume::MUTED;
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stereo(a: u32, b: u32) -> ChannelVolumes {
+        let mut cv = ChannelVolumes::default();
+        cv.set_len(2);
+        cv.get_mut()[0] = Volume(a);
+        cv.get_mut()[1] = Volume(b);
+        cv
+    }
+
+    #[test]
+    fn interpolate_midpoint() {
+        let cv = interpolate(&stereo(0, 1000), &stereo(1000, 0), 0.5);
+        assert_eq!(cv.get(), &[Volume(500), Volume(500)]);
+    }
+
+    #[test]
+    fn restarted_sleep_keeps_its_deadline() {
+        let start = Instant::now();
+        let mut fade = Fade::new(FadeKind::Sleep, "sink".into(), stereo(1000, 1000), stereo(0, 0), Duration::from_secs(60));
+        fade.start = start;
+        let now = start + Duration::from_secs(20);
+        let restarted = fade.restarted_from(stereo(600, 400), now);
+        assert_eq!(restarted.kind, FadeKind::Sleep);
+        assert_eq!(restarted.from.get(), stereo(600, 400).get());
+        assert_eq!(restarted.volume_at(now).get(), stereo(600, 400).get());
+        assert_eq!(restarted.remaining(now), Duration::from_secs(40));
+        assert_eq!(restarted.volume_at(now + Duration::from_secs(20)).get(), stereo(300, 200).get());
+    }
+
+    #[test]
+    fn interpolate_clamps_progress() {
+        let from = stereo(100, 200);
+        let to = stereo(300, 400);
+        assert_eq!(interpolate(&from, &to, -1.0).get(), from.get());
+        assert_eq!(interpolate(&from, &to, 2.0).get(), to.get());
+    }
+
+    #[test]
+    fn fade_progress_and_remaining() {
+        let mut f = Fade::new(FadeKind::Sleep, "s".into(), stereo(1000, 1000), silenced(&stereo(1000, 1000)), Duration::from_secs(10));
+        f.start = Instant::now() - Duration::from_secs(5);
+        let now = Instant::now();
+        assert!((f.progress(now) - 0.5).abs() < 0.05);
+        assert!(f.remaining(now) <= Duration::from_secs(5));
+        assert!(f.mutes_at_end());
+        assert_eq!(f.step_interval(), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn zero_length_fade_is_complete() {
+        let f = Fade::new(FadeKind::Volume, "s".into(), stereo(0, 0), stereo(10, 10), Duration::ZERO);
+        assert_eq!(f.progress(Instant::now()), 1.0);
+        assert_eq!(f.step_interval(), MIN_STEP_INTERVAL);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::HashMap, fs, io, path::PathBuf, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::battery::Part;
+
+/// Samples kept per device; older ones are dropped.
+const MAX_SAMPLES: usize = 500;
+/// Only samples this recent count towards the discharge rate, so it follows
+/// the current usage (e.g. ANC on, a call) rather than yesterday's.
+const RATE_WINDOW_SECS: u64 = 6 * 3600;
+/// A rate needs at least this much time and this big a drop to mean anything;
+/// most devices report in 5 or 10 percent steps.
+const MIN_RATE_SPAN_SECS: u64 = 15 * 60;
+const MIN_RATE_DROP: u8 = 5;
+
+/// One battery reading, at a Unix time in seconds. Earbuds are sampled by
+/// their lowest bud, so `part` says which one the reading came from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub at: u64,
+    pub percentage: u8,
+    pub charging: bool,
+    #[serde(default)]
+    pub part: Part,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct DeviceHistory {
+    samples: Vec<Sample>,
+    /// When the device was last seen charging (or its level rising).
+    last_charged: Option<u64>,
+}
+
+/// Battery samples per device, persisted as JSON so the estimate survives restarts.
+#[derive(Default)]
+pub struct BatteryHistory {
+    path: Option<PathBuf>,
+    devices: HashMap<String, DeviceHistory>,
+}
+
+impl BatteryHistory {
+    /// Load the history from `path` (best-effort: a missing or corrupt file starts empty).
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let devices = path.as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        Self { path, devices }
+    }
+
+    /// Add a sample for `key` unless it repeats the previous one. A charging
+    /// sample or a rise in the same part's level marks a charge; a jump from
+    /// the lowest bud switching doesn't. Returns `true` if recorded.
+    pub fn record(&mut self, key: &str, sample: Sample) -> bool {
+        let device = self.devices.entry(key.to_string()).or_default();
+        if let Some(last) = device.samples.last() {
+            if last.percentage == sample.percentage && last.charging == sample.charging && last.part == sample.part {
+                return false;
+            }
+            if sample.part == last.part && sample.percentage > last.percentage {
+                device.last_charged = Some(sample.at);
+            }
+        }
+        if sample.charging {
+            device.last_charged = Some(sample.at);
+        }
+        device.samples.push(sample);
+        if device.samples.len() > MAX_SAMPLES {
+            device.samples.remove(0);
+        }
+        true
+    }
+
+    /// Estimated time until `key`'s battery is empty, from the discharge rate of
+    /// the part last sampled since the last charge (within the last few hours).
+    /// `None` while charging or without enough samples.
+    pub fn eta(&self, key: &str, now: u64) -> Option<Duration> {
+        let device = self.devices.get(key)?;
+        let last = device.samples.last()?;
+        if last.charging {
+            return None;
+        }
+        let since = device.last_charged.unwrap_or(0).max(now.saturating_sub(RATE_WINDOW_SECS));
+        let first = device.samples.iter().find(|s| s.at >= since && !s.charging && s.part == last.part)?;
+        let span = last.at.saturating_sub(first.at);
+        let drop = first.percentage.saturating_sub(last.percentage);
+        if span < MIN_RATE_SPAN_SECS || drop < MIN_RATE_DROP {
+            return None;
+        }
+        // Time since the last sample has already been spent.
+        let left = (last.percentage as u64 * span / drop as u64).saturating_sub(now.saturating_sub(last.at));
+        Some(Duration::from_secs(left))
+    }
+
+    /// When `key` was last seen charging.
+    pub fn last_charged(&self, key: &str) -> Option<u64> {
+        self.devices.get(key)?.last_charged
+    }
+
+    /// Write the history back to its file (atomically, via a temporary file).
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string(&self.devices)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Short duration such as `3h10m` or `45m`.
+pub fn format_duration(d: Duration) -> String {
+    let minutes = d.as_secs() / 60;
+    if minutes >= 60 {
+        format!("{}h{:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(at: u64, percentage: u8, charging: bool) -> Sample {
+        Sample { at, percentage, charging, part: Part::Main }
+    }
+
+    #[test]
+    fn record_skips_repeats_and_marks_charges() {
+        let mut h = BatteryHistory::default();
+        assert!(h.record("a", sample(0, 50, false)));
+        assert!(!h.record("a", sample(60, 50, false)));
+        assert_eq!(h.last_charged("a"), None);
+        assert!(h.record("a", sample(120, 50, true)));
+        assert_eq!(h.last_charged("a"), Some(120));
+        assert!(h.record("a", sample(180, 60, false)));
+        assert_eq!(h.last_charged("a"), Some(180));
+    }
+
+    #[test]
+    fn switching_buds_is_not_a_charge() {
+        let bud = |at, percentage, part| Sample { at, percentage, charging: false, part };
+        let mut h = BatteryHistory::default();
+        assert!(h.record("a", bud(0, 90, Part::Left)));
+        assert!(h.record("a", bud(100, 60, Part::Right)));
+        assert!(h.record("a", bud(1800, 80, Part::Left)));
+        assert!(h.record("a", bud(1900, 60, Part::Right)));
+        assert_eq!(h.last_charged("a"), None);
+        // The left bud dropped 20% in an hour: 70% lasts 3h30m.
+        assert!(h.record("a", bud(3600, 70, Part::Left)));
+        assert_eq!(h.eta("a", 3600), Some(Duration::from_secs(3 * 3600 + 1800)));
+        assert!(h.record("a", bud(3800, 85, Part::Left)));
+        assert_eq!(h.last_charged("a"), Some(3800));
+    }
+
+    #[test]
+    fn eta_from_discharge_since_last_charge() {
+        let mut h = BatteryHistory::default();
+        h.record("a", sample(0, 100, true));
+        h.record("a", sample(100, 100, false));
+        h.record("a", sample(100 + 1800, 90, false));
+        // 10% per 30 minutes, 90% left: 4h30m.
+        assert_eq!(h.eta("a", 1900), Some(Duration::from_secs(4 * 3600 + 1800)));
+        assert_eq!(h.eta("a", 1900 + 600), Some(Duration::from_secs(4 * 3600 + 1200)));
+        h.record("a", sample(2000, 90, true));
+        assert_eq!(h.eta("a", 2000), None);
+    }
+
+    #[test]
+    fn eta_needs_span_and_drop() {
+        let mut h = BatteryHistory::default();
+        h.record("a", sample(0, 80, false));
+        h.record("a", sample(60, 75, false));
+        assert_eq!(h.eta("a", 60), None);
+        assert_eq!(h.eta("b", 60), None);
+    }
+
+    #[test]
+    fn format_short_durations() {
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 600)), "3h10m");
+        assert_eq!(format_duration(Duration::from_secs(45 * 60 + 30)), "45m");
+    }
+}
@@ -1,23 +1,51 @@
 mod protocol;
 use protocol::*;
-
-use std::{error::Error, io::{self, Write}, process::{Command, Stdio, ChildStdin}, sync::{Arc, Mutex}, thread, rc::Rc, cell::{Cell, RefCell}, os::unix::io::RawFd};
+mod fade;
+use fade::{Fade, FadeKind};
+mod memory;
+use memory::{SavedVolume, VolumeMemory};
+mod rules;
+use rules::SinkRules;
+mod bluez;
+use bluez::BluezEvent;
+mod upower;
+mod battery;
+mod notify;
+mod history;
+mod output;
+mod wob;
+mod mpris;
+mod service;
+mod logging;
+use wob::Wob;
+use output::{Backend, BatterySegment, Block};
+use history::{BatteryHistory, Sample};
+use battery::{Battery, BatteryIcons, Grade, Level};
+
+use std::{error::Error, fs, io::{self, Write}, process::{Command, Stdio}, sync::{Arc, Mutex, RwLock, mpsc, atomic::{AtomicBool, AtomicI32, Ordering}}, thread, rc::Rc, cell::{Cell, RefCell}, os::unix::io::RawFd};
 
 use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
 use zbus::blocking::{Connection, Proxy};
 
 use envconfig::Envconfig;
 use regex::Regex;
-use std::time::{Duration, Instant};
-use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::io::BufRead;
+use std::os::unix::net::UnixListener;
 
 use libpulse_binding as pulse;
 use pulse::context::{Context, FlagSet as ContextFlagSet, State as ContextState};
-use pulse::context::subscribe::InterestMaskSet;
-use pulse::context::introspect::SinkInfo;
+use pulse::context::subscribe::{Facility, InterestMaskSet};
+use pulse::context::introspect::{SinkInfo, SinkInputInfo};
+use pulse::channelmap::Position;
 use pulse::mainloop::standard::{Mainloop, IterateResult};
-use pulse::mainloop::api::Mainloop as _; // trait providing new_io_event()
+use pulse::mainloop::api::Mainloop as MainloopTrait; // trait providing new_io_event()
 use pulse::mainloop::events::io::FlagSet as IoFlagSet;
+use pulse::mainloop::events::timer::TimeEvent;
+use pulse::time::MonotonicTs;
 use pulse::callbacks::ListResult;
 use pulse::volume::{ChannelVolumes, Volume};
 use pulse::def::SinkState;
@@ -30,10 +58,31 @@ const CHAR_AUDIO_LOW:    char = '\u{1F508}';
 const CHAR_AUDIO_MEDIUM: char = '\u{1F509}';
 /// Character representing a high volume level.
 const CHAR_AUDIO_HIGH:   char = '\u{1F50A}';
+/// Character shown next to the time left on a running sleep timer.
+const CHAR_SLEEP_TIMER:  char = '\u{1F4A4}';
+/// Character after a battery level left over from the previous run.
+const CHAR_STALE:        char = '?';
+/// Character in front of Bluetooth connect/disconnect progress.
+const CHAR_HEADSET:      char = '\u{1F3A7}';
+
+/// Default layout of the status text (see [`expand_format`]).
+const DEFAULT_FORMAT: &str = "{icon} {volume}% {battery} {sleep} {media}";
+
+/// Wait before watching MPRIS players again after losing the session bus.
+const MEDIA_RETRY: Duration = Duration::from_secs(5);
+
+/// Highest volume, in percent, that `volume <n>` and `SetVolume` may set.
+const MAX_SET_VOLUME: u16 = 150;
 
-/// Battery cache TTL and poll interval
-const BT_BATTERY_TTL_SECS: u64 = 30;
-const BT_POLL_INTERVAL_SECS: u64 = 31;
+/// How long after the block itself changed a volume the spike guard stays quiet.
+const OWN_CHANGE_GRACE: Duration = Duration::from_secs(1);
+
+/// Signals the bar sends when it's hidden and shown again (announced in the
+/// i3bar header). Output and battery polling pause in between.
+const STOP_SIGNAL: libc::c_int = libc::SIGUSR1;
+const CONT_SIGNAL: libc::c_int = libc::SIGUSR2;
+/// How long the outcome of a Bluetooth connect/disconnect stays in the block.
+const BT_STATUS_SECS: u64 = 3;
 
 #[derive(Envconfig)]
 pub struct Config {
@@ -45,10 +94,170 @@ pub struct Config {
     pub show_device_name: bool,
     #[envconfig(from = "SHOW_BT_BATTERY", default="true")]
     pub show_bt_battery: bool,
+    /// Register `org.i3blocks.VolumePw` on the session bus for scripts.
+    #[envconfig(from = "DBUS_SERVICE", default="false")]
+    pub dbus_service: bool,
+    /// Log to a file at this level and up: `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    #[envconfig(from = "LOG_LEVEL", default="off")]
+    pub log_level: String,
+    /// Log file, instead of `$XDG_STATE_HOME/i3blocks-volume-pw/log`.
+    #[envconfig(from = "LOG_FILE")]
+    pub log_file: Option<String>,
+    /// Show the track of an MPRIS media player as `{media}` and control it with clicks.
+    #[envconfig(from = "SHOW_MEDIA", default="false")]
+    pub show_media: bool,
+    /// Also look up batteries through UPower (USB/2.4GHz dongle headsets, HID++ devices).
+    #[envconfig(from = "USE_UPOWER", default="true")]
+    pub use_upower: bool,
+    /// How long a battery reading is trusted before it's looked up again.
+    #[envconfig(from = "BT_BATTERY_TTL_SECS", default="30")]
+    pub bt_battery_ttl_secs: u64,
+    /// Fallback battery poll interval (BlueZ signals normally keep the cache
+    /// fresh; polling only covers devices that don't emit them).
+    #[envconfig(from = "BT_POLL_INTERVAL_SECS", default="31")]
+    pub bt_poll_interval_secs: u64,
+    /// Give up on `bluetoothctl info` after this long.
+    #[envconfig(from = "BLUETOOTHCTL_TIMEOUT_SECS", default="5")]
+    pub bluetoothctl_timeout_secs: u64,
     #[envconfig(from = "PRINT_HEADER", default="false")]
     pub print_header: bool,
+    /// `i3blocks` (one JSON object per line), `i3bar` (a full i3bar/swaybar
+    /// `status_command`: header plus an infinite array), `waybar` (custom-module JSON),
+    /// `polybar` (tail script with `%{A}` action tags), `lemonbar` or `plain` (text lines).
+    #[envconfig(from = "OUTPUT_FORMAT", default="i3blocks")]
+    pub output_format: String,
+    /// Unix socket accepting text commands (`up`, `mute`, ...), one per line.
+    /// Defaults to `$XDG_RUNTIME_DIR/i3blocks-volume-pw/command.sock` for bars that send nothing on stdin.
+    #[envconfig(from = "COMMAND_SOCKET")]
+    pub command_socket: Option<String>,
     #[envconfig(from = "USE_WOB", default="false")]
     pub use_wob: bool,
+    /// Shell command line of the wob spawned with `USE_WOB`.
+    #[envconfig(from = "WOB_COMMAND", default="wob")]
+    pub wob_command: String,
+    /// Existing wob FIFO or Unix socket to write to instead of spawning wob.
+    #[envconfig(from = "WOB_PATH")]
+    pub wob_path: Option<String>,
+    /// Tag muted and over-100% values with the wob ≥0.15 styles `muted` and `overamplified`.
+    #[envconfig(from = "WOB_STYLES", default="false")]
+    pub wob_styles: bool,
+    /// Show volume, mute and device changes as a desktop notification with a progress bar.
+    #[envconfig(from = "USE_NOTIFY_OSD", default="false")]
+    pub use_notify_osd: bool,
+    /// Ramp volume and mute changes over this many milliseconds (0 = jump).
+    #[envconfig(from = "FADE_MS", default="0")]
+    pub fade_ms: u64,
+    /// Length of the sleep-timer fade started by shift + middle click.
+    #[envconfig(from = "SLEEP_TIMER_MINUTES", default="30")]
+    pub sleep_timer_minutes: u32,
+    /// Remember each sink's volume/mute and restore it when the sink reappears.
+    #[envconfig(from = "REMEMBER_VOLUME", default="false")]
+    pub remember_volume: bool,
+    /// Comma-separated sink names left alone by `REMEMBER_VOLUME` (`prefix*` allowed).
+    #[envconfig(from = "REMEMBER_VOLUME_EXCLUDE", default="")]
+    pub remember_volume_exclude: String,
+    /// JSON array of per-sink step/max/default volume rules (see [`SinkRules::parse`]).
+    #[envconfig(from = "SINK_RULES", default="")]
+    pub sink_rules: String,
+    /// Undo external volume rises of more than this many percent in one step.
+    #[envconfig(from = "SPIKE_GUARD_PERCENT")]
+    pub spike_guard_percent: Option<u16>,
+    /// Let guarded spikes through up to this volume instead of the previous level.
+    #[envconfig(from = "SPIKE_GUARD_CEILING")]
+    pub spike_guard_ceiling: Option<u16>,
+    /// What to do when output falls back from headphones to speakers: `none`, `mute` or `cap`.
+    #[envconfig(from = "SPEAKER_FALLBACK", default="none")]
+    pub speaker_fallback: String,
+    /// Volume in percent the speakers are capped at with `SPEAKER_FALLBACK=cap`.
+    #[envconfig(from = "SPEAKER_FALLBACK_CAP", default="30")]
+    pub speaker_fallback_cap: u16,
+    /// Layout of the status text; placeholders: `{icon}`, `{volume}`, `{battery}`,
+    /// `{battery_eta}`, `{battery_charged}`, `{sleep}`, `{codec}`, `{profile}`, `{media}`.
+    #[envconfig(from = "FORMAT", default="{icon} {volume}% {battery} {sleep} {media}")]
+    pub format: String,
+    /// Battery glyphs: `emoji`, `nerd`, `fontawesome`, or five comma-separated
+    /// glyphs for empty, low, half, full and charging.
+    #[envconfig(from = "BATTERY_ICONS", default="emoji")]
+    pub battery_icons: String,
+    /// Color of the battery segment (via Pango markup), separate from the block color.
+    #[envconfig(from = "BATTERY_COLOR")]
+    pub battery_color: Option<String>,
+    /// Color of the battery segment while it's low (30% or less) and not charging.
+    #[envconfig(from = "BATTERY_LOW_COLOR")]
+    pub battery_low_color: Option<String>,
+    /// Comma-separated Bluetooth codecs shown in `LOW_QUALITY_COLOR`.
+    #[envconfig(from = "LOW_QUALITY_CODECS", default="cvsd,msbc")]
+    pub low_quality_codecs: String,
+    #[envconfig(from = "LOW_QUALITY_COLOR", default="#FFA500")]
+    pub low_quality_color: String,
+    /// MAC of the Bluetooth headset shift + left click connects or disconnects.
+    #[envconfig(from = "BT_FAVOURITE")]
+    pub bt_favourite: Option<String>,
+    /// Comma-separated battery percentages that trigger a desktop notification, e.g. `20,10`.
+    #[envconfig(from = "LOW_BATTERY_NOTIFY", default="")]
+    pub low_battery_notify: String,
+}
+
+/// Text command for a real-time signal: `SIGRTMIN+1` raises the volume,
+/// `+2` lowers it and `+3` toggles mute (for bars without a click protocol).
+fn signal_command(sig: libc::c_int) -> Option<&'static str> {
+    match sig - libc::SIGRTMIN() {
+        1 => Some("up"),
+        2 => Some("down"),
+        3 => Some("mute"),
+        _ => None,
+    }
+}
+
+/// Strip the i3bar click-stream framing from a line of stdin: the opening `[`
+/// and the `,` in front of every click after the first.
+fn unframe_click_line(text: &str) -> &str {
+    let text = text.trim();
+    let text = text.strip_prefix('[').unwrap_or(text).trim_start();
+    text.strip_prefix(',').unwrap_or(text).trim()
+}
+
+/// Policy applied when output moves from a headphone-type device to speakers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FallbackPolicy {
+    None,
+    Mute,
+    /// Lower the speakers to at most this volume (percent).
+    Cap(u16),
+}
+
+impl FallbackPolicy {
+    fn from_config(policy: &str, cap: u16) -> Result<Self, Box<dyn Error>> {
+        match policy {
+            "none" | "" => Ok(Self::None),
+            "mute" => Ok(Self::Mute),
+            "cap" => Ok(Self::Cap(cap)),
+            other => Err(format!("Invalid SPEAKER_FALLBACK `{}` (expected none, mute or cap)", other).into()),
+        }
+    }
+}
+
+/// Split a comma-separated config value into its non-empty, trimmed items.
+fn config_list(s: &str) -> Vec<String> {
+    s.split(',').map(str::trim).filter(|i| !i.is_empty()).map(str::to_string).collect()
+}
+
+/// Directory for per-session files that shouldn't outlive the login:
+/// `$XDG_RUNTIME_DIR/i3blocks-volume-pw`.
+fn runtime_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|v| !v.is_empty())
+        .map(|d| PathBuf::from(d).join("i3blocks-volume-pw"))
+}
+
+/// Directory for persistent state files: `$XDG_STATE_HOME/i3blocks-volume-pw`,
+/// falling back to `~/.local/state/i3blocks-volume-pw`.
+fn state_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))?;
+    Some(base.join("i3blocks-volume-pw"))
 }
 
 lazy_static! {
@@ -100,16 +309,104 @@ fn mac_from_sink_name(s: &str) -> Option<String> {
     Some(candidate.to_uppercase())
 }
 
+/// The device the battery lookups should find a battery for (the displayed sink's).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BatteryTarget {
+    /// Cache key: the MAC for Bluetooth sinks, else the sink name.
+    pub key: String,
+    pub mac: Option<String>,
+    /// `device.serial` of the sink, e.g. `Logitech_G533_Gaming_Headset_1A2B3C4D`.
+    pub serial: Option<String>,
+    /// `device.product.name` of the sink.
+    pub model: Option<String>,
+    /// Human-readable device name, for notifications.
+    pub name: String,
+}
+
+/// Battery target for a sink: Bluetooth sinks by MAC, USB sinks by serial/model.
+/// Other sinks (built-in cards, HDMI) have no battery.
+fn battery_target(sink: &Sink) -> Option<BatteryTarget> {
+    let mac = if sink.sink_name.starts_with("bluez_output.") {
+        mac_from_sink_name(&sink.sink_name)
+    } else {
+        None
+    };
+    if mac.is_none() && sink.bus.as_deref() != Some("usb") {
+        return None;
+    }
+    Some(BatteryTarget {
+        key: mac.clone().unwrap_or_else(|| sink.sink_name.clone()),
+        mac,
+        serial: sink.serial.clone(),
+        model: sink.model.clone(),
+        name: sink.device_name.clone(),
+    })
+}
+
+/// One device's entry in `BT_BATTERY_CACHE`.
+struct CachedBattery {
+    read_at: Instant,
+    battery: Battery,
+    /// Lowest low-battery threshold already notified (see [`notify::low_battery_threshold`]).
+    notified: Option<u8>,
+    /// Loaded from the previous run's cache file and not read since.
+    stale: bool,
+}
+
+/// Battery lookup settings from `Config`, set once at startup.
+struct BatterySettings {
+    ttl: Duration,
+    bluetoothctl_timeout: Duration,
+    /// Where `BT_BATTERY_CACHE` is saved for the next run, if anywhere.
+    cache_path: Option<PathBuf>,
+}
+
+impl Default for BatterySettings {
+    fn default() -> Self {
+        Self { ttl: Duration::from_secs(30), bluetoothctl_timeout: Duration::from_secs(5), cache_path: None }
+    }
+}
+
 lazy_static! {
-    static ref BT_BATTERY_CACHE: Mutex<HashMap<String, (Instant, u8)>> = Mutex::new(HashMap::new());
+    static ref BT_BATTERY_CACHE: Mutex<HashMap<String, CachedBattery>> = Mutex::new(HashMap::new());
+    static ref BATTERY_SETTINGS: RwLock<BatterySettings> = RwLock::new(BatterySettings::default());
+    /// Timestamped battery samples per device, for the time-remaining estimate.
+    static ref BATTERY_HISTORY: Mutex<BatteryHistory> = Mutex::new(BatteryHistory::default());
+    /// The one system-bus connection shared by BlueZ and UPower lookups and the signal watcher.
+    static ref SYSTEM_BUS: Mutex<Option<Connection>> = Mutex::new(None);
+    /// The session-bus connection shared by notifications and MPRIS.
+    static ref SESSION_BUS: Mutex<Option<Connection>> = Mutex::new(None);
+}
+
+/// The shared system-bus connection, opened on first use. A failed connect is
+/// retried on the next call.
+fn system_bus() -> Option<Connection> {
+    let mut guard = SYSTEM_BUS.lock().unwrap();
+    if guard.is_none() {
+        *guard = Connection::system().ok();
+    }
+    guard.clone()
+}
+
+/// Drop the shared system-bus connection after a watch on it ended (e.g.
+/// dbus restarted), so the next [`system_bus`] call connects again.
+fn reset_system_bus() {
+    *SYSTEM_BUS.lock().unwrap() = None;
+}
+
+/// The shared session-bus connection, opened on first use.
+fn session_bus() -> Option<Connection> {
+    let mut guard = SESSION_BUS.lock().unwrap();
+    if guard.is_none() {
+        *guard = Connection::session().ok();
+    }
+    guard.clone()
 }
 
-/// Build a list of candidate BlueZ device object paths for a given MAC.
-/// Example for MAC `AA:BB:CC:DD:EE:FF` -> `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF`,
-/// `/org/bluez/hci1/dev_AA_BB_CC_DD_EE_FF`, ...
-fn bluez_device_paths_for_mac(mac: &str, max_hci: usize) -> Vec<String> {
-    let dev = mac.replace(':', "_").to_uppercase();
-    (0..max_hci).map(|i| format!("/org/bluez/hci{}/dev_{}", i, dev)).collect()
+/// Drop the shared session-bus connection after a watch on it ended (e.g. the
+/// bus restarted), so the next [`session_bus`] call connects again.
+fn reset_session_bus() {
+    *SESSION_BUS.lock().unwrap() = None;
 }
 
 /// Parse `bluetoothctl info <mac>` output for a battery percentage (best-effort).
@@ -123,49 +420,184 @@ fn parse_bluetoothctl_info_output(s: &str) -> Option<u8> {
     None
 }
 
-/// Return a cached battery value if it's still fresh.
-fn cached_bt_battery(mac: &str) -> Option<u8> {
-    let key = mac.to_uppercase();
+/// Return a cached battery value if it's still fresh. `key` is a MAC or, for
+/// non-Bluetooth devices, a sink name (see [`BatteryTarget::key`]).
+fn cached_bt_battery(key: &str) -> Option<Battery> {
+    let key = key.to_uppercase();
     let guard = BT_BATTERY_CACHE.lock().unwrap();
-    if let Some((ts, v)) = guard.get(&key) {
-        if Instant::now().duration_since(*ts) < Duration::from_secs(BT_BATTERY_TTL_SECS) {
-            return Some(*v);
+    let ttl = BATTERY_SETTINGS.read().unwrap().ttl;
+    if let Some(entry) = guard.get(&key) {
+        if !entry.stale && Instant::now().duration_since(entry.read_at) < ttl {
+            return Some(entry.battery.clone());
+        }
+    }
+    None
+}
+
+/// The battery to display for `key`: a fresh reading, or the previous run's
+/// last known value (flagged `true` as stale) until the device is read again.
+fn displayed_battery(key: &str) -> Option<(Battery, bool)> {
+    if let Some(v) = cached_bt_battery(key) {
+        return Some((v, false));
+    }
+    let guard = BT_BATTERY_CACHE.lock().unwrap();
+    guard.get(&key.to_uppercase())
+        .filter(|entry| entry.stale)
+        .map(|entry| (entry.battery.clone(), true))
+}
+
+/// Store a freshly read battery value in the cache.
+fn store_bt_battery(key: &str, v: Battery) {
+    {
+        let mut guard = BT_BATTERY_CACHE.lock().unwrap();
+        let entry = guard.entry(key.to_uppercase()).or_insert(CachedBattery {
+            read_at: Instant::now(),
+            battery: Battery::default(),
+            notified: None,
+            stale: false,
+        });
+        entry.read_at = Instant::now();
+        entry.battery = v.clone();
+        entry.stale = false;
+    }
+    if let Some(lowest) = v.lowest() {
+        let mut history = BATTERY_HISTORY.lock().unwrap();
+        let sample = Sample { at: unix_now(), percentage: lowest.percentage, charging: lowest.charging, part: lowest.part };
+        if history.record(&key.to_uppercase(), sample) {
+            if let Err(e) = history.save() {
+                error!("Error saving battery history: {}", e);
+            }
+        }
+    }
+    let path = BATTERY_SETTINGS.read().unwrap().cache_path.clone();
+    if let Some(path) = path {
+        if let Err(e) = save_battery_cache(&path) {
+            error!("Error saving battery cache: {}", e);
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, for timestamps that outlive the process.
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Write the last known battery of every device to `path` (atomically, via a
+/// temporary file), for the next run to show right away.
+fn save_battery_cache(path: &std::path::Path) -> io::Result<()> {
+    let snapshot: HashMap<String, Battery> = BT_BATTERY_CACHE.lock().unwrap().iter()
+        .map(|(key, entry)| (key.clone(), entry.battery.clone()))
+        .collect();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(&snapshot)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Seed `BT_BATTERY_CACHE` from the previous run's cache file (best-effort);
+/// the entries stay marked stale until read again.
+fn load_battery_cache(path: &std::path::Path) {
+    let saved: HashMap<String, Battery> = match fs::read_to_string(path).ok().and_then(|s| serde_json::from_str(&s).ok()) {
+        Some(saved) => saved,
+        None => return,
+    };
+    let mut guard = BT_BATTERY_CACHE.lock().unwrap();
+    for (key, battery) in saved {
+        guard.entry(key).or_insert(CachedBattery { read_at: Instant::now(), battery, notified: None, stale: true });
+    }
+}
+
+/// Check the cached battery of `key` against the low-battery `thresholds` and
+/// record the outcome, so each threshold notifies once per device. Returns the
+/// percentage and threshold to notify about. Charging batteries never notify.
+fn low_battery_alert(key: &str, thresholds: &[u8]) -> Option<(u8, u8)> {
+    let mut guard = BT_BATTERY_CACHE.lock().unwrap();
+    let entry = guard.get_mut(&key.to_uppercase())?;
+    let lowest = *entry.battery.lowest()?;
+    if lowest.charging {
+        return None;
+    }
+    let (threshold, notified) = notify::low_battery_threshold(thresholds, entry.notified, lowest.percentage);
+    entry.notified = notified;
+    threshold.map(|t| (lowest.percentage, t))
+}
+
+/// Send a desktop notification if `target`'s battery just crossed one of `thresholds`.
+fn notify_low_battery(target: &BatteryTarget, thresholds: &[u8]) {
+    if let Some((percentage, threshold)) = low_battery_alert(&target.key, thresholds) {
+        info!("{} battery at {}%, notifying (threshold {}%)", target.key, percentage, threshold);
+        if let Err(e) = notify::notify_low_battery(&target.name, percentage, thresholds.last() == Some(&threshold)) {
+            warn!("Failed to send the low-battery notification: {}", e);
+        }
+    }
+}
+
+/// Update one level of a cached battery (e.g. one earbud from a signal),
+/// keeping the device's other levels if they're still fresh.
+fn store_bt_battery_level(key: &str, level: Level) {
+    let mut battery = cached_bt_battery(key).unwrap_or_default();
+    battery.set(level);
+    store_bt_battery(key, battery);
+}
+
+/// Look up the battery of `target` (best-effort, may block): BlueZ and
+/// `bluetoothctl` for Bluetooth devices, then UPower if enabled. A fresh cache
+/// entry short-circuits the lookup.
+fn get_battery(target: &BatteryTarget, use_upower: bool) -> Option<Battery> {
+    if let Some(v) = cached_bt_battery(&target.key) {
+        debug!("battery of {}: {:?} (cached)", target.key, v.levels());
+        return Some(v);
+    }
+    if let Some(v) = target.mac.as_deref().and_then(get_bt_battery) {
+        debug!("battery of {}: {:?} (BlueZ)", target.key, v.levels());
+        return Some(v);
+    }
+    if use_upower {
+        if let Some(v) = system_bus().and_then(|conn| upower::find_battery(&conn, target)) {
+            debug!("battery of {}: {:?} (UPower)", target.key, v.levels());
+            store_bt_battery(&target.key, v.clone());
+            return Some(v);
         }
     }
+    debug!("no battery found for {}", target.key);
     None
 }
 
 /// Query BlueZ `org.bluez.Battery1` for a device MAC (best-effort).
-/// Iterates available hci adapters (hci0..hciN) until a Battery1 property is found.
+/// The device object is found through BlueZ's ObjectManager (see [`bluez::find_device`]).
 /// Falls back to `bluetoothctl info <mac>` if D-Bus lookups fail.
-/// Returns `None` on any error or if Battery1 is not present on any adapter.
-fn get_bt_battery(mac: &str) -> Option<u8> {
+/// Returns `None` on any error or if the device has no Battery1.
+fn get_bt_battery(mac: &str) -> Option<Battery> {
     // Check cache first
     if let Some(v) = cached_bt_battery(mac) {
         return Some(v);
     }
 
-    let conn = Connection::system().ok()?;
-    let key = mac.to_uppercase();
-
-    // Try the first few adapters (80/20: most systems use hci0/hci1).
-    for path in bluez_device_paths_for_mac(mac, 8) {
-        if let Ok(proxy) = Proxy::new(&conn, "org.bluez", path.as_str(), "org.bluez.Battery1") {
-            if let Ok(p) = proxy.get_property::<u8>("Percentage") {
-                // update cache
-                let mut guard = BT_BATTERY_CACHE.lock().unwrap();
-                guard.insert(key.clone(), (Instant::now(), p));
-                return Some(p);
+    if let Some(conn) = system_bus() {
+        if let Some(device) = bluez::find_device(&conn, mac) {
+            let v: Battery = device.batteries.iter().filter_map(|path| {
+                let (_, part) = bluez::battery_owner(path)?;
+                let proxy = Proxy::new(&conn, "org.bluez", path.as_str(), "org.bluez.Battery1").ok()?;
+                let percentage = proxy.get_property::<u8>("Percentage").ok()?;
+                Some(Level { part, percentage, charging: false })
+            }).collect();
+            if !v.is_empty() {
+                store_bt_battery(mac, v.clone());
+                return Some(v);
             }
         }
     }
 
     // D-Bus failed; try CLI fallback (bluetoothctl info <mac>) with a timeout so a
     // hung BlueZ stack can't wedge the caller indefinitely.
-    if let Some(out) = bluetoothctl_info_with_timeout(mac, Duration::from_secs(5)) {
-        if let Some(v) = parse_bluetoothctl_info_output(&out) {
-            let mut guard = BT_BATTERY_CACHE.lock().unwrap();
-            guard.insert(key.clone(), (Instant::now(), v));
+    let timeout = BATTERY_SETTINGS.read().unwrap().bluetoothctl_timeout;
+    if let Some(out) = bluetoothctl_info_with_timeout(mac, timeout) {
+        if let Some(p) = parse_bluetoothctl_info_output(&out) {
+            debug!("battery of {} from bluetoothctl: {}%", mac, p);
+            let v = Battery::single(p, false);
+            store_bt_battery(mac, v.clone());
             return Some(v);
         }
     }
@@ -186,6 +618,7 @@ fn bluetoothctl_info_with_timeout(mac: &str, timeout: Duration) -> Option<String
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
         .spawn()
+        .map_err(|e| info!("Failed to run bluetoothctl: {}", e))
         .ok()?;
 
     let (tx, rx) = mpsc::channel();
@@ -203,6 +636,7 @@ fn bluetoothctl_info_with_timeout(mac: &str, timeout: Duration) -> Option<String
         }
         Err(_) => {
             // Timed out: kill the child so it doesn't linger.
+            warn!("bluetoothctl info {} timed out after {:?}", mac, timeout);
             let _ = child.kill();
             None
         }
@@ -222,6 +656,25 @@ struct Sink {
     sink_name: String,
     got_sink_name: bool,
     battery: Option<u8>,
+    /// Headphones or a headset rather than speakers.
+    headphones: bool,
+    /// Bluetooth codec (`api.bluez5.codec`), e.g. `ldac`.
+    codec: Option<String>,
+    /// Bluetooth profile (`api.bluez5.profile`), e.g. `a2dp-sink`.
+    profile: Option<String>,
+    /// `device.bus`, e.g. `usb`, `bluetooth`, `pci`.
+    bus: Option<String>,
+    /// `device.serial` and `device.product.name`, used to match UPower devices.
+    serial: Option<String>,
+    model: Option<String>,
+    /// Server index, which sink inputs refer to.
+    index: u32,
+    /// `device.description`, active port and sample spec, for the detailed view.
+    description: Option<String>,
+    port: Option<String>,
+    sample_spec: Option<String>,
+    /// Volume per channel, e.g. `("FL", 50)`.
+    channel_volumes: Vec<(String, u16)>,
 }
 
 impl Sink {
@@ -237,6 +690,17 @@ impl Sink {
         self.sink_name = String::new();
         self.got_sink_name = false;
         self.battery = None;
+        self.headphones = false;
+        self.codec = None;
+        self.profile = None;
+        self.bus = None;
+        self.serial = None;
+        self.model = None;
+        self.index = 0;
+        self.description = None;
+        self.port = None;
+        self.sample_spec = None;
+        self.channel_volumes.clear();
     }
 }
 
@@ -260,9 +724,89 @@ fn sink_from_info(info: &SinkInfo) -> Sink {
         sink.device_name = v;
         sink.got_device_name = true;
     }
+    sink.headphones = is_headphone_like(
+        info.proplist.get_str("device.form_factor").as_deref(),
+        info.active_port.as_ref().and_then(|p| p.name.as_deref()),
+    );
+    sink.codec = info.proplist.get_str("api.bluez5.codec").filter(|s| !s.is_empty());
+    sink.profile = info.proplist.get_str("api.bluez5.profile").filter(|s| !s.is_empty());
+    sink.bus = info.proplist.get_str("device.bus").filter(|s| !s.is_empty());
+    sink.serial = info.proplist.get_str("device.serial").filter(|s| !s.is_empty());
+    sink.model = info.proplist.get_str("device.product.name").filter(|s| !s.is_empty());
+    sink.index = info.index;
+    sink.description = info.description.as_ref().map(|d| d.to_string()).filter(|d| !d.is_empty());
+    sink.port = info.active_port.as_ref()
+        .and_then(|p| p.description.as_deref().or(p.name.as_deref()))
+        .map(str::to_string);
+    sink.sample_spec = Some(info.sample_spec.print());
+    sink.channel_volumes = info.channel_map.get().iter().zip(info.volume.get())
+        .map(|(pos, v)| (channel_label(*pos), (v.0 as f64 / Volume::NORMAL.0 as f64 * 100.0).round() as u16))
+        .collect();
     sink
 }
 
+/// Short name of a channel position, e.g. `FL`.
+fn channel_label(pos: Position) -> String {
+    match pos {
+        Position::Mono => "M".to_string(),
+        Position::FrontLeft => "FL".to_string(),
+        Position::FrontRight => "FR".to_string(),
+        Position::FrontCenter => "FC".to_string(),
+        Position::RearLeft => "RL".to_string(),
+        Position::RearRight => "RR".to_string(),
+        Position::RearCenter => "RC".to_string(),
+        Position::Lfe => "LFE".to_string(),
+        Position::SideLeft => "SL".to_string(),
+        Position::SideRight => "SR".to_string(),
+        other => Position::to_string(other).map(|s| s.into_owned()).unwrap_or_else(|| "?".to_string()),
+    }
+}
+
+/// An application playing to a sink (an uncorked sink input).
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct PlayingApp {
+    /// Index of the sink it plays to.
+    sink: u32,
+    name: String,
+    /// `application.process.id`, to find the app's MPRIS player.
+    pid: Option<u32>,
+}
+
+/// Build a [`PlayingApp`] from a sink input, skipping paused (corked) streams.
+fn playing_app_from_info(info: &SinkInputInfo) -> Option<PlayingApp> {
+    if info.corked {
+        return None;
+    }
+    let name = info.proplist.get_str("application.name")
+        .or_else(|| info.name.as_ref().map(|n| n.to_string()))
+        .filter(|n| !n.is_empty())?;
+    Some(PlayingApp {
+        sink: info.sink,
+        name,
+        pid: info.proplist.get_str("application.process.id").and_then(|p| p.parse().ok()),
+    })
+}
+
+/// Display name for a PipeWire Bluetooth codec id, e.g. `msbc` -> `mSBC`.
+fn codec_display_name(codec: &str) -> String {
+    match codec.to_ascii_lowercase().as_str() {
+        "msbc" => "mSBC".to_string(),
+        "sbc_xq" => "SBC-XQ".to_string(),
+        "aptx" => "aptX".to_string(),
+        "aptx_hd" => "aptX HD".to_string(),
+        "aptx_ll" => "aptX LL".to_string(),
+        "faststream" => "FastStream".to_string(),
+        other => other.to_uppercase(),
+    }
+}
+
+/// Whether a sink's form factor or active port (e.g. `analog-output-headphones`)
+/// marks it as headphones or a headset.
+fn is_headphone_like(form_factor: Option<&str>, port_name: Option<&str>) -> bool {
+    matches!(form_factor, Some("headphone" | "headset" | "hands-free"))
+        || port_name.is_some_and(|p| p.contains("headphone") || p.contains("headset"))
+}
+
 /// Gets the output to be displayed to the user.
 /// The first element of the tuple is the status line,
 /// and the second element is the volume percentage to display to the user.
@@ -338,15 +882,17 @@ pub fn get_output(default_sink_node: Option<String>, lines: Vec<String>, include
     // Best-effort: read a cached BlueZ battery value if this looks like a Bluetooth
     // sink. The actual (potentially blocking) D-Bus/bluetoothctl lookup happens on
     // the bt-poller thread, never here on the event loop.
-    let mut bt_battery: Option<u8> = None;
+    let mut battery: Option<(Battery, bool)> = None;
     if include_bt_battery && s.sink_name.starts_with("bluez_output.") {
         if let Some(mac) = mac_from_sink_name(&s.sink_name) {
-            bt_battery = cached_bt_battery(&mac);
+            battery = displayed_battery(&mac);
         }
     }
 
     // Delegate rendering to a pure helper so tests can mock the battery/formatting.
-    render_sink_output(s, include_device_name, bt_battery)
+    let (battery, battery_stale) = battery.map_or((None, false), |(b, stale)| (Some(b), stale));
+    let opts = RenderOptions { include_device_name, battery, battery_stale, ..Default::default() };
+    render_sink_output(s, &opts)
 }
 
 /// Pick the sink to display: prefer a RUNNING sink, then the reported default sink,
@@ -357,10 +903,145 @@ fn choose_sink<'a>(sinks: &'a [Sink], default_sink_node: Option<&str>) -> Option
         .or_else(|| sinks.first())
 }
 
-/// Render JSON output for a single `Sink` (pure, test-friendly).
-fn render_sink_output(s: &Sink, include_device_name: bool, bt_battery: Option<u8>) -> Result<(String, u16), Box<dyn Error>> {
-    let mut output = Output::default();
+/// Everything besides the sink itself that affects the rendered line.
+#[derive(Default)]
+struct RenderOptions<'a> {
+    /// Layout of the status text; empty means [`DEFAULT_FORMAT`].
+    format: &'a str,
+    include_device_name: bool,
+    battery: Option<Battery>,
+    /// The battery is the previous run's last known value, not a current reading.
+    battery_stale: bool,
+    /// Estimated time until the battery is empty.
+    battery_eta: Option<Duration>,
+    /// Time since the battery was last charging.
+    battery_charged: Option<Duration>,
+    /// Battery glyphs; `None` means the emoji theme.
+    battery_icons: Option<&'a BatteryIcons>,
+    /// Color of the battery segment, and while it's low.
+    battery_color: Option<&'a str>,
+    battery_low_color: Option<&'a str>,
+    /// Time left on a running sleep timer.
+    sleep_remaining: Option<Duration>,
+    /// Volume ceiling from the sink's rule; louder is flagged urgent (default 100%).
+    max_volume: Option<u16>,
+    /// Flag the block urgent regardless of volume (e.g. after a guarded spike).
+    urgent: bool,
+    /// Text color while the sink is on a low-quality Bluetooth codec.
+    low_quality_color: Option<&'a str>,
+    /// Transient message appended to the text, e.g. Bluetooth connect progress.
+    status: Option<&'a str>,
+    /// Show the detailed view as the text (it's always the tooltip).
+    detailed: bool,
+    /// Applications playing to the sink.
+    playing: Vec<String>,
+    /// The server's default sink, which may not be the displayed one.
+    default_sink: Option<&'a str>,
+    /// Track of the shown media player, e.g. `▶ Artist – Title`.
+    media: Option<String>,
+}
+
+/// What the block shows; right click cycles through them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum DisplayMode {
+    #[default]
+    Compact,
+    DeviceName,
+    Detailed,
+}
+
+impl DisplayMode {
+    fn next(self) -> Self {
+        match self {
+            Self::Compact => Self::DeviceName,
+            Self::DeviceName => Self::Detailed,
+            Self::Detailed => Self::Compact,
+        }
+    }
+}
+
+/// The battery part of the text, with [`CHAR_STALE`] after a stale value.
+fn render_battery(opts: &RenderOptions, render: impl Fn(&Battery, &BatteryIcons) -> String) -> String {
+    let icons = opts.battery_icons.cloned().unwrap_or_default();
+    let mut out = opts.battery.as_ref().map(|b| render(b, &icons)).unwrap_or_default();
+    if opts.battery_stale && !out.is_empty() {
+        out.push(CHAR_STALE);
+    }
+    out
+}
+
+/// Color of the battery segment: the low color while the lowest level is low
+/// and not charging, else the battery color.
+fn battery_segment_color<'a>(opts: &RenderOptions<'a>) -> Option<&'a str> {
+    let lowest = opts.battery.as_ref()?.lowest()?;
+    if !lowest.charging && Grade::of(lowest.percentage) <= Grade::Low {
+        opts.battery_low_color.or(opts.battery_color)
+    } else {
+        opts.battery_color
+    }
+}
+
+/// Substitute `{name}` placeholders in `format`, then collapse the whitespace
+/// left behind by empty values. Unknown placeholders are kept verbatim.
+fn expand_format(format: &str, values: &[(&str, String)]) -> String {
+    let mut out = format.to_string();
+    for (name, value) in values {
+        out = out.replace(&format!("{{{}}}", name), value);
+    }
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Lines of the detailed view: description, port, sample spec, codec,
+/// battery, per-channel volumes, playing applications and the default sink.
+fn render_details(s: &Sink, opts: &RenderOptions) -> Vec<String> {
+    let name = s.description.clone()
+        .or_else(|| (!s.device_name.is_empty()).then(|| s.device_name.clone()))
+        .unwrap_or_else(|| s.sink_name.clone());
+    let mut lines = vec![name];
+    if let Some(port) = &s.port {
+        lines.push(format!("Port: {}", port));
+    }
+    if let Some(spec) = &s.sample_spec {
+        lines.push(format!("Format: {}", spec));
+    }
+    if let Some(codec) = &s.codec {
+        let profile = s.profile.as_ref().map(|p| format!(" ({})", p)).unwrap_or_default();
+        lines.push(format!("Codec: {}{}", codec_display_name(codec), profile));
+    }
+    let battery = render_battery(opts, Battery::render);
+    if !battery.is_empty() {
+        let mut line = format!("Battery: {}", battery);
+        if let Some(eta) = opts.battery_eta {
+            line.push_str(&format!(", ~{} left", history::format_duration(eta)));
+        }
+        if let Some(charged) = opts.battery_charged {
+            line.push_str(&format!(", charged {} ago", history::format_duration(charged)));
+        }
+        lines.push(line);
+    }
+    let volume = if s.channel_volumes.is_empty() {
+        format!("{}%", s.volume_percent)
+    } else {
+        s.channel_volumes.iter().map(|(ch, v)| format!("{} {}%", ch, v)).collect::<Vec<_>>().join(" ")
+    };
+    lines.push(format!("Volume: {}{}", volume, if s.mute { " (muted)" } else { "" }));
+    if !opts.playing.is_empty() {
+        lines.push(format!("Playing: {}", opts.playing.join(", ")));
+    }
+    if let Some(media) = &opts.media {
+        lines.push(format!("Media: {}", media));
+    }
+    match opts.default_sink {
+        Some(d) if d == s.sink_name => lines.push("Default sink".to_string()),
+        Some(d) => lines.push(format!("Default sink: {}", d)),
+        None => {}
+    }
+    lines
+}
 
+/// Render a single `Sink` into the block every output backend starts from
+/// (pure, test-friendly). Also returns the volume for wob (0 while muted).
+fn render_block(s: &Sink, opts: &RenderOptions) -> (Block, u16) {
     let icon_char = if s.mute {
         CHAR_AUDIO_MUTED
     } else if s.volume_percent <= 20 {
@@ -371,50 +1052,167 @@ fn render_sink_output(s: &Sink, include_device_name: bool, bt_battery: Option<u8
         CHAR_AUDIO_HIGH
     };
 
-    // Base text includes volume and optional BT battery indicator
-    let mut base_text = format!("{} {}%", icon_char, s.volume_percent);
-    if let Some(b) = bt_battery {
-        base_text.push(' ');
-        base_text.push_str(&format!("🔋{}%", b));
-    }
+    // Base text includes volume and optional BT battery/codec/sleep-timer details
+    // (short_text only gets the lowest of several batteries).
+    let format = if opts.format.is_empty() { DEFAULT_FORMAT } else { opts.format };
+    let battery_full = render_battery(opts, Battery::render);
+    let mut values: Vec<(&str, String)> = vec![
+        ("icon", icon_char.to_string()),
+        ("volume", s.volume_percent.to_string()),
+        ("battery", battery_full.clone()),
+        ("battery_eta", opts.battery_eta.map(|d| format!("~{}", history::format_duration(d))).unwrap_or_default()),
+        ("battery_charged", opts.battery_charged
+            .map(|d| format!("charged {} ago", history::format_duration(d)))
+            .unwrap_or_default()),
+        ("sleep", opts.sleep_remaining
+            .map(|left| format!("{}{}m", CHAR_SLEEP_TIMER, left.as_secs().div_ceil(60)))
+            .unwrap_or_default()),
+        ("codec", s.codec.as_deref().map(codec_display_name).unwrap_or_default()),
+        ("profile", s.profile.clone().unwrap_or_default()),
+        ("media", opts.media.clone().unwrap_or_default()),
+    ];
+    let base_text = expand_format(format, &values);
+    let multi_battery = opts.battery.as_ref().is_some_and(|b| b.levels().len() > 1);
+    let battery_short = if multi_battery { render_battery(opts, Battery::render_compact) } else { battery_full.clone() };
+    let short_text = if multi_battery {
+        if let Some((_, battery)) = values.iter_mut().find(|(name, _)| *name == "battery") {
+            *battery = battery_short.clone();
+        }
+        expand_format(format, &values)
+    } else {
+        base_text.clone()
+    };
+    let (base_text, short_text) = match opts.status {
+        Some(status) => (format!("{} {}", base_text, status), format!("{} {}", short_text, status)),
+        None => (base_text, short_text),
+    };
 
-    if include_device_name && !s.device_name.is_empty() {
-        output.full_text = format!("{} [{}]", base_text, s.device_name);
-        output.short_text = Some(base_text);
+    let mut block = Block {
+        volume: s.volume_percent,
+        muted: s.mute,
+        bluetooth: s.sink_name.starts_with("bluez_output."),
+        device_name: s.device_name.clone(),
+        sink_name: s.sink_name.clone(),
+        ..Default::default()
+    };
+    let details = render_details(s, opts);
+    if opts.detailed {
+        block.full_text = details.join(" | ");
+        block.short_text = Some(short_text);
+    } else if opts.include_device_name && !s.device_name.is_empty() {
+        block.full_text = format!("{} [{}]", base_text, s.device_name);
+        block.short_text = Some(short_text);
     } else {
-        output.full_text = base_text;
-        output.short_text = None;
+        block.full_text = base_text;
+        block.short_text = if multi_battery { Some(short_text) } else { None };
     }
 
-    if s.volume_percent > 100 {
-        output.urgent = Some(true);
-    }
+    block.tooltip = Some(details.join("\n"));
+    block.urgent = opts.urgent || s.volume_percent > opts.max_volume.unwrap_or(100);
+    block.color = opts.low_quality_color.map(str::to_string);
+    block.battery = battery_segment_color(opts)
+        .filter(|_| !battery_full.is_empty())
+        .map(|color| BatterySegment { full: battery_full, short: battery_short, color: color.to_string() });
+
+    (block, if s.mute { 0 } else { s.volume_percent })
+}
 
-    let json_output = serde_json::to_string(&output)
+/// Render i3blocks JSON output for a single `Sink`.
+fn render_sink_output(s: &Sink, opts: &RenderOptions) -> Result<(String, u16), Box<dyn Error>> {
+    let (block, volume) = render_block(s, opts);
+    let json_output = output::I3blocks { print_header: false }.render(&block, false)
         .map_err(|e| format!("Failed to serialize output: {}", e))?;
-    Ok((json_output, if s.mute { 0 } else { s.volume_percent }))
+    Ok((json_output, volume))
 }
 
 /// Mutable display/runtime state shared between the PulseAudio callbacks and the
 /// event loop. Everything lives on the single event-loop thread, so a plain
 /// `Rc<RefCell<..>>` is sufficient (no locking).
 struct State {
-    show_device_name: bool,
+    display: DisplayMode,
+    /// Applications playing to any sink, as of the last redraw.
+    playing: Vec<PlayingApp>,
+    /// MPRIS players, kept current by the mpris-watcher thread (`SHOW_MEDIA`).
+    media_players: Option<Arc<Mutex<Vec<mpris::Player>>>>,
+    /// Bus name of the player shown, which media clicks control.
+    media_player: Option<String>,
     show_bt_battery: bool,
     previous_line: String,
+    /// Writing to stdout failed on a bar that sends nothing on stdin: it's
+    /// gone, and the run ends as on EOF.
+    output_closed: bool,
     last_volume: u16,
     first_update: bool,
+    /// Serializes blocks for the bar reading stdout.
+    backend: Box<dyn Backend>,
+    /// The bar is hidden (stop signal): nothing is printed and the battery
+    /// poller idles until the cont signal.
+    paused: Arc<AtomicBool>,
     /// Default sink name as last reported by the server (for selection fallback).
     default_sink: Option<String>,
     /// Currently displayed sink + its raw volume, used to apply click actions.
     cur_sink_name: Option<String>,
     cur_volume: ChannelVolumes,
+    cur_volume_pct: u16,
     cur_mute: bool,
-    wob_stdin: Option<ChildStdin>,
-    /// MAC of the current Bluetooth sink (if any), read by the bt-poller thread.
-    current_bluez_mac: Arc<Mutex<Option<String>>>,
+    wob: Option<Wob>,
+    /// Feeds the D-Bus service's properties (`DBUS_SERVICE`).
+    service: Option<mpsc::Sender<service::Snapshot>>,
+    /// Feeds the notification OSD thread (`USE_NOTIFY_OSD`).
+    osd: Option<mpsc::Sender<notify::VolumeOsd>>,
+    /// Battery device behind the current sink (if any), read by the bt-poller thread.
+    battery_target: Arc<Mutex<Option<BatteryTarget>>>,
+    /// MAC of the headset connected/disconnected by [`Action::ToggleBluetooth`].
+    bt_favourite: Option<String>,
+    /// Progress or outcome of a running connect/disconnect, shown in the block.
+    bt_status: Arc<Mutex<Option<String>>>,
+    /// Write end of the wakeup pipe, for helper threads to trigger a redraw.
+    wake_fd: RawFd,
+    /// Wakes the bt-poller early, e.g. when a different battery-powered sink shows up.
+    bt_kick: Option<mpsc::Sender<()>>,
+    /// Ramp length for volume/mute changes; zero applies them at once.
+    fade_duration: Duration,
+    sleep_timer_minutes: u32,
+    /// Running volume ramp (including the sleep timer), advanced by `fade_timer`.
+    fade: Option<Fade>,
+    /// Timer of the current session driving `fade`; dropped with the session.
+    fade_timer: Option<FadeTimer>,
+    /// Per-sink volume memory (`None` when disabled).
+    volume_memory: Option<VolumeMemory>,
+    /// Sink names in the previous sink list, to spot sinks that (re)appear.
+    known_sinks: Option<HashSet<String>>,
+    rules: SinkRules,
+    /// Spike guard threshold and ceiling in percent (`None` = guard disabled).
+    spike_guard: Option<u16>,
+    spike_ceiling: Option<u16>,
+    /// Set when the guard undid a spike; shows the block urgent until the next click.
+    spike_guarded: bool,
+    /// When the block last changed a sink volume itself (never a spike).
+    own_volume_change: Option<Instant>,
+    format: String,
+    /// Lower-case codec ids considered low quality, and the color that flags them.
+    low_quality_codecs: Vec<String>,
+    low_quality_color: String,
+    battery_icons: BatteryIcons,
+    battery_color: Option<String>,
+    battery_low_color: Option<String>,
+    fallback_policy: FallbackPolicy,
+    /// Displayed and default sink of the previous list, with their headphone flag.
+    prev_displayed: Option<(String, bool)>,
+    prev_default: Option<(String, bool)>,
+}
+
+impl State {
+    /// Whether rendering uses the sink inputs: the detailed view and tooltips
+    /// list the playing applications, and the media player is matched by them.
+    fn needs_sink_inputs(&self) -> bool {
+        self.display == DisplayMode::Detailed || self.backend.shows_tooltip() || self.media_players.is_some()
+    }
 }
 
+/// Mainloop timer event used to step fades.
+type FadeTimer = TimeEvent<<Mainloop as MainloopTrait>::MI>;
+
 /// PipeWire/PulseAudio volume control for an i3blocks blocklet.
 ///
 /// Holds a single persistent client connection to the PulseAudio-compatible
@@ -435,23 +1233,43 @@ impl Control {
     /// wakeup pipe becomes readable. Reconnects automatically if the server
     /// restarts, and returns only when stdin closes (the parent goes away).
     pub fn run(self) -> Result<(), Box<dyn Error>> {
-        // Optional i3bar protocol header.
-        if self.config.print_header {
-            let header = Header { version: 1, click_events: Some(true), ..Default::default() };
-            println!("{}", serde_json::to_string(&header)?);
+        let log_file = self.config.log_file.clone().filter(|p| !p.is_empty()).map(PathBuf::from)
+            .or_else(|| state_dir().map(|d| d.join("log")));
+        logging::init(logging::parse_level(&self.config.log_level)?, log_file)?;
+        info!("starting, output format `{}`", self.config.output_format);
+        let rules = SinkRules::parse(&self.config.sink_rules)?;
+        if self.config.show_bt_battery {
+            let cache_path = runtime_dir().map(|d| d.join("battery.json"));
+            if let Some(path) = &cache_path {
+                load_battery_cache(path);
+            }
+            *BATTERY_HISTORY.lock().unwrap() = BatteryHistory::load(state_dir().map(|d| d.join("battery-history.json")));
+            *BATTERY_SETTINGS.write().unwrap() = BatterySettings {
+                ttl: Duration::from_secs(self.config.bt_battery_ttl_secs),
+                bluetoothctl_timeout: Duration::from_secs(self.config.bluetoothctl_timeout_secs),
+                cache_path,
+            };
+        }
+        let poll_interval = Duration::from_secs(self.config.bt_poll_interval_secs);
+        let battery_icons = BatteryIcons::from_config(&self.config.battery_icons)?;
+        let low_battery_thresholds = notify::parse_thresholds(&config_list(&self.config.low_battery_notify))?;
+        let fallback_policy = FallbackPolicy::from_config(&self.config.speaker_fallback, self.config.speaker_fallback_cap)?;
+
+        // Output backend, and its preamble (e.g. the i3bar protocol header).
+        let backend = output::from_config(&self.config.output_format, self.config.print_header, &self.config.volume_control_app)?;
+        for line in backend.preamble() {
+            println!("{}", line);
         }
 
-        // Optional `wob` overlay process.
-        let wob_stdin = if self.config.use_wob {
-            match Command::new("wob").stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() {
-                Ok(mut child) => child.stdin.take(),
-                Err(e) => { eprintln!("Failed to spawn wob: {}", e); None }
-            }
-        } else {
-            None
+        // Optional `wob` overlay: our own process, or an existing FIFO/socket.
+        let wob = match self.config.wob_path.as_deref().filter(|p| !p.is_empty()) {
+            Some(path) => Some(Wob::path(PathBuf::from(path), self.config.wob_styles)),
+            None => self.config.use_wob.then(|| Wob::spawn(&self.config.wob_command, self.config.wob_styles)),
         };
 
-        let current_bluez_mac: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let osd = if self.config.use_notify_osd { Some(notify::spawn_volume_osd()?) } else { None };
+
+        let battery_target: Arc<Mutex<Option<BatteryTarget>>> = Arc::new(Mutex::new(None));
 
         // Self-pipe so the (blocking) Bluetooth battery thread can wake the event
         // loop: it writes a byte after warming the cache, the read end is polled as
@@ -460,37 +1278,175 @@ impl Control {
         let (bt_pipe_rd, bt_pipe_wr) = make_pipe()?;
         set_nonblocking(bt_pipe_rd);
 
-        // Background Bluetooth battery poller: the D-Bus / bluetoothctl lookup may
-        // block, so it runs off the event loop and signals via the pipe.
-        if self.config.show_bt_battery {
-            let mac_slot = current_bluez_mac.clone();
+        // Stop/cont signals arrive through their own self-pipe (see `on_signal`).
+        let rt = libc::SIGRTMIN();
+        // Stop/cont are only taken over when the header announced them; otherwise
+        // a stray SIGUSR1 keeps its default effect instead of freezing the block.
+        let mut signals = vec![rt + 1, rt + 2, rt + 3];
+        if backend.announces_stop_signals() {
+            signals.extend([STOP_SIGNAL, CONT_SIGNAL]);
+        }
+        let sig_pipe_rd = install_signal_pipe(&signals)?;
+
+        // Text commands from the command socket reach the loop through a pipe,
+        // one line each, and are handled like stdin lines.
+        let (cmd_pipe_rd, cmd_pipe_wr) = make_pipe()?;
+        set_nonblocking(cmd_pipe_rd);
+        let socket_path = self.config.command_socket.clone().filter(|p| !p.is_empty()).map(PathBuf::from)
+            .or_else(|| (!backend.reads_stdin()).then(|| runtime_dir().map(|d| d.join("command.sock"))).flatten());
+        if let Some(path) = socket_path {
+            if socket_in_use(&path) {
+                warn!("Command socket {} belongs to another running instance; not listening on it", path.display());
+            } else {
+                spawn_command_socket(path, cmd_pipe_wr)?;
+            }
+        }
+        // The D-Bus service queues its method calls on the same pipe.
+        let service = if self.config.dbus_service {
+            Some(service::spawn(cmd_pipe_wr).map_err(|e| format!("Failed to register {}: {}", service::BUS_NAME, e))?)
+        } else {
+            None
+        };
+        let fds = EventFds { battery: bt_pipe_rd, signals: sig_pipe_rd, commands: cmd_pipe_rd };
+        let paused = Arc::new(AtomicBool::new(false));
+
+        // Battery updates. A watcher thread listens for BlueZ battery signals on
+        // the shared system-bus connection and wakes the loop right away; the
+        // poller thread does the (potentially blocking) BlueZ / bluetoothctl /
+        // UPower lookup when the sink changes and, as a fallback for devices
+        // without signals, every `BT_POLL_INTERVAL_SECS`. Both check the
+        // displayed device's battery against the low-battery thresholds.
+        let bt_kick = if self.config.show_bt_battery {
+            let (kick_tx, kick_rx) = mpsc::channel::<()>();
+            let target_slot = battery_target.clone();
+            let use_upower = self.config.use_upower;
+            let thresholds = low_battery_thresholds.clone();
+            let paused = paused.clone();
             thread::Builder::new().name("bt-poller".to_string()).spawn(move || {
                 loop {
-                    let mac = mac_slot.lock().unwrap().clone();
-                    if let Some(mac) = mac {
-                        if get_bt_battery(&mac).is_some() {
-                            let _ = unsafe { libc::write(bt_pipe_wr, [1u8].as_ptr() as *const libc::c_void, 1) };
+                    let target = target_slot.lock().unwrap().clone().filter(|_| !paused.load(Ordering::Relaxed));
+                    if let Some(target) = target {
+                        if get_battery(&target, use_upower).is_some() {
+                            notify_low_battery(&target, &thresholds);
+                            wake_event_loop(bt_pipe_wr);
                         }
                     }
-                    thread::sleep(Duration::from_secs(BT_POLL_INTERVAL_SECS));
+                    if let Err(mpsc::RecvTimeoutError::Disconnected) = kick_rx.recv_timeout(poll_interval) {
+                        thread::sleep(poll_interval);
+                    }
                 }
             })?;
-        }
+
+            let watcher_kick = kick_tx.clone();
+            let target_slot = battery_target.clone();
+            let thresholds = low_battery_thresholds;
+            thread::Builder::new().name("bt-watcher".to_string()).spawn(move || {
+                loop {
+                    let res = bluez::watch_battery_signals(|event| match event {
+                        BluezEvent::Battery { mac, part, percentage } => {
+                            debug!("BlueZ battery signal: {} {:?} {}%", mac, part, percentage);
+                            store_bt_battery_level(&mac, Level { part, percentage, charging: false });
+                            let target = target_slot.lock().unwrap().clone();
+                            if let Some(target) = target.filter(|t| t.key == mac) {
+                                notify_low_battery(&target, &thresholds);
+                            }
+                            wake_event_loop(bt_pipe_wr);
+                        }
+                        BluezEvent::DeviceAdded { .. } => {
+                            let _ = watcher_kick.send(());
+                        }
+                    });
+                    // System bus unavailable or dropped; the poller covers until
+                    // we're back on a fresh connection.
+                    match res {
+                        Ok(()) => info!("BlueZ battery watch ended, reconnecting in {:?}", poll_interval),
+                        Err(e) => info!("BlueZ battery watch failed, reconnecting in {:?}: {}", poll_interval, e),
+                    }
+                    reset_system_bus();
+                    thread::sleep(poll_interval);
+                }
+            })?;
+            Some(kick_tx)
+        } else {
+            None
+        };
+
+        // MPRIS players. The watcher updates the list on player signals and
+        // wakes the loop through the battery pipe, which redraws.
+        let media_players = if self.config.show_media {
+            let players: Arc<Mutex<Vec<mpris::Player>>> = Arc::new(Mutex::new(Vec::new()));
+            let slot = players.clone();
+            thread::Builder::new().name("mpris-watcher".to_string()).spawn(move || {
+                loop {
+                    let res = mpris::watch_players(|list| {
+                        *slot.lock().unwrap() = list;
+                        wake_event_loop(bt_pipe_wr);
+                    });
+                    match res {
+                        Ok(()) => info!("MPRIS watch ended, reconnecting in {:?}", MEDIA_RETRY),
+                        Err(e) => info!("MPRIS watch failed, reconnecting in {:?}: {}", MEDIA_RETRY, e),
+                    }
+                    reset_session_bus();
+                    slot.lock().unwrap().clear();
+                    wake_event_loop(bt_pipe_wr);
+                    thread::sleep(MEDIA_RETRY);
+                }
+            })?;
+            Some(players)
+        } else {
+            None
+        };
 
         // Display/runtime state persists across reconnects so the bar keeps showing
         // the last value through a brief server restart.
         let state = Rc::new(RefCell::new(State {
-            show_device_name: self.config.show_device_name,
+            display: if self.config.show_device_name { DisplayMode::DeviceName } else { DisplayMode::Compact },
+            playing: Vec::new(),
+            media_players,
+            media_player: None,
             show_bt_battery: self.config.show_bt_battery,
             previous_line: String::new(),
+            output_closed: false,
             last_volume: 0,
             first_update: true,
+            backend,
+            paused,
             default_sink: None,
             cur_sink_name: None,
             cur_volume: ChannelVolumes::default(),
+            cur_volume_pct: 0,
             cur_mute: false,
-            wob_stdin,
-            current_bluez_mac,
+            wob,
+            osd,
+            service,
+            battery_target,
+            bt_favourite: self.config.bt_favourite.clone().filter(|m| !m.is_empty()),
+            bt_status: Arc::new(Mutex::new(None)),
+            wake_fd: bt_pipe_wr,
+            bt_kick,
+            fade_duration: Duration::from_millis(self.config.fade_ms),
+            sleep_timer_minutes: self.config.sleep_timer_minutes,
+            fade: None,
+            fade_timer: None,
+            volume_memory: self.config.remember_volume.then(|| VolumeMemory::load(
+                state_dir().map(|d| d.join("volumes.json")),
+                config_list(&self.config.remember_volume_exclude),
+            )),
+            known_sinks: None,
+            rules,
+            spike_guard: self.config.spike_guard_percent,
+            spike_ceiling: self.config.spike_guard_ceiling,
+            spike_guarded: false,
+            own_volume_change: None,
+            format: self.config.format.clone(),
+            low_quality_codecs: config_list(&self.config.low_quality_codecs.to_ascii_lowercase()),
+            low_quality_color: self.config.low_quality_color.clone(),
+            battery_icons,
+            battery_color: self.config.battery_color.clone().filter(|c| !c.is_empty()),
+            battery_low_color: self.config.battery_low_color.clone().filter(|c| !c.is_empty()),
+            fallback_policy,
+            prev_displayed: None,
+            prev_default: None,
         }));
 
         // Set by the stdin callback on EOF (parent closed); a real, permanent exit.
@@ -503,9 +1459,10 @@ impl Control {
         let mut delay = Duration::from_millis(200);
         let max_delay = Duration::from_secs(2);
         loop {
-            match self.run_session(&state, &quit, bt_pipe_rd, &mut delay) {
+            match self.run_session(&state, &quit, &fds, &mut delay) {
                 SessionEnd::Eof => return Ok(()),
                 SessionEnd::Lost => {
+                    info!("server connection lost, reconnecting in {:?}", delay);
                     thread::sleep(delay);
                     delay = (delay * 2).min(max_delay);
                 }
@@ -516,7 +1473,8 @@ impl Control {
     /// Run a single connected session until the parent closes stdin (`Eof`) or the
     /// server connection is lost (`Lost`). On a successful connect, `delay` is reset
     /// to its minimum so the next disconnect retries promptly.
-    fn run_session(&self, state: &Rc<RefCell<State>>, quit: &Rc<Cell<bool>>, bt_pipe_rd: RawFd, delay: &mut Duration) -> SessionEnd {
+    fn run_session(&self, state: &Rc<RefCell<State>>, quit: &Rc<Cell<bool>>, fds: &EventFds, delay: &mut Duration) -> SessionEnd {
+        let EventFds { battery: bt_pipe_rd, signals: sig_pipe_rd, commands: cmd_pipe_rd } = *fds;
         let mut mainloop = match Mainloop::new() {
             Some(m) => m,
             None => return SessionEnd::Lost,
@@ -525,7 +1483,8 @@ impl Control {
             Some(c) => Rc::new(RefCell::new(c)),
             None => return SessionEnd::Lost,
         };
-        if ctx.borrow_mut().connect(None, ContextFlagSet::NOFLAGS, None).is_err() {
+        if let Err(e) = ctx.borrow_mut().connect(None, ContextFlagSet::NOFLAGS, None) {
+            info!("connecting to the server failed: {}", e);
             return SessionEnd::Lost;
         }
 
@@ -537,49 +1496,68 @@ impl Control {
             }
             match ctx.borrow().get_state() {
                 ContextState::Ready => break,
-                ContextState::Failed | ContextState::Terminated => return SessionEnd::Lost,
+                ContextState::Failed | ContextState::Terminated => {
+                    info!("connecting to the server failed: {}", ctx.borrow().errno());
+                    return SessionEnd::Lost;
+                }
                 _ => {}
             }
         }
+        info!("connected to {}", ctx.borrow().get_server().unwrap_or_default());
         *delay = Duration::from_millis(200); // connected: reset backoff
 
-        // Subscribe to sink and server changes only. Nothing reacts to client
-        // events, so our own introspection queries can never re-trigger a refresh.
+        // Subscribe to sink, sink input and server changes only. Nothing reacts
+        // to client events, so our own introspection queries can never re-trigger
+        // a refresh. Sink input events only redraw when something shows them.
         {
             let ctx_sub = ctx.clone();
             let state_sub = state.clone();
-            ctx.borrow_mut().set_subscribe_callback(Some(Box::new(move |_facility, _op, _idx| {
-                request_redraw(&ctx_sub, &state_sub);
+            ctx.borrow_mut().set_subscribe_callback(Some(Box::new(move |facility, op, idx| {
+                debug!("subscribe event: {:?} {:?} #{}", facility, op, idx);
+                if facility == Some(Facility::SinkInput) {
+                    if state_sub.borrow().needs_sink_inputs() {
+                        redraw(&ctx_sub, &state_sub, false);
+                    }
+                } else {
+                    request_redraw(&ctx_sub, &state_sub);
+                }
             })));
-            ctx.borrow_mut().subscribe(InterestMaskSet::SINK | InterestMaskSet::SERVER, |_| {});
+            ctx.borrow_mut().subscribe(InterestMaskSet::SINK | InterestMaskSet::SINK_INPUT | InterestMaskSet::SERVER, |_| {});
         }
 
-        // stdin (clicks) as an IO event source.
-        let stdin_ev = {
+        // stdin (clicks) as an IO event source. Bars without a click protocol
+        // may hand us a closed stdin, so it's not watched there.
+        let stdin_ev = if !state.borrow().backend.reads_stdin() {
+            None
+        } else {
             let ctx_c = ctx.clone();
             let state_c = state.clone();
             let quit_c = quit.clone();
             let app = self.config.volume_control_app.clone();
             let delta = self.config.audio_delta as i32;
             let mut acc: Vec<u8> = Vec::new();
-            let mut buf = [0u8; 1024];
             mainloop.new_io_event(0, IoFlagSet::INPUT, Box::new(move |mut ev, _fd, _flags| {
-                loop {
-                    let n = unsafe { libc::read(0, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
-                    if n > 0 {
-                        acc.extend_from_slice(&buf[..n as usize]);
-                    } else if n == 0 {
-                        // EOF: parent gone. Stop listening and ask the loop to quit.
-                        quit_c.set(true);
-                        ev.enable(IoFlagSet::NULL);
-                        return;
-                    } else {
-                        break; // EAGAIN
-                    }
+                let (lines, eof) = read_lines(0, &mut acc);
+                for text in lines {
+                    handle_click(unframe_click_line(&text), &ctx_c, &state_c, &app, delta);
+                }
+                if eof {
+                    // EOF: parent gone. Stop listening and ask the loop to quit.
+                    quit_c.set(true);
+                    ev.enable(IoFlagSet::NULL);
                 }
-                while let Some(pos) = acc.iter().position(|&b| b == b'\n') {
-                    let line: Vec<u8> = acc.drain(..=pos).collect();
-                    let text = String::from_utf8_lossy(&line);
+            }))
+        };
+
+        // Text commands from the command socket.
+        let cmd_ev = {
+            let ctx_c = ctx.clone();
+            let state_c = state.clone();
+            let app = self.config.volume_control_app.clone();
+            let delta = self.config.audio_delta as i32;
+            let mut acc: Vec<u8> = Vec::new();
+            mainloop.new_io_event(cmd_pipe_rd, IoFlagSet::INPUT, Box::new(move |_ev, _fd, _flags| {
+                for text in read_lines(cmd_pipe_rd, &mut acc).0 {
                     handle_click(text.trim(), &ctx_c, &state_c, &app, delta);
                 }
             }))
@@ -592,10 +1570,67 @@ impl Control {
             let mut drain = [0u8; 64];
             mainloop.new_io_event(bt_pipe_rd, IoFlagSet::INPUT, Box::new(move |_ev, _fd, _flags| {
                 while unsafe { libc::read(bt_pipe_rd, drain.as_mut_ptr() as *mut libc::c_void, drain.len()) } > 0 {}
+                // While hidden, skip the server round trip; the cont signal redraws in full.
+                if state_p.borrow().paused.load(Ordering::Relaxed) {
+                    return;
+                }
                 request_redraw(&ctx_p, &state_p);
             }))
         };
 
+        // Stop/cont signals: pause while the bar is hidden, then redraw in full.
+        // Real-time signals carry volume commands.
+        let sig_ev = {
+            let ctx_s = ctx.clone();
+            let state_s = state.clone();
+            let app = self.config.volume_control_app.clone();
+            let delta = self.config.audio_delta as i32;
+            let mut signals = [0u8; 64];
+            mainloop.new_io_event(sig_pipe_rd, IoFlagSet::INPUT, Box::new(move |_ev, _fd, _flags| {
+                loop {
+                    let n = unsafe { libc::read(sig_pipe_rd, signals.as_mut_ptr() as *mut libc::c_void, signals.len()) };
+                    if n <= 0 {
+                        break;
+                    }
+                    for &sig in &signals[..n as usize] {
+                        match libc::c_int::from(sig) {
+                            STOP_SIGNAL => state_s.borrow().paused.store(true, Ordering::Relaxed),
+                            CONT_SIGNAL => {
+                                {
+                                    let mut s = state_s.borrow_mut();
+                                    s.paused.store(false, Ordering::Relaxed);
+                                    s.previous_line.clear();
+                                    if let Some(kick) = &s.bt_kick {
+                                        let _ = kick.send(());
+                                    }
+                                }
+                                request_redraw(&ctx_s, &state_s);
+                            }
+                            other => {
+                                if let Some(command) = signal_command(other) {
+                                    handle_click(command, &ctx_s, &state_s, &app, delta);
+                                }
+                            }
+                        }
+                    }
+                }
+            }))
+        };
+
+        // Fade ticker. It fires once right away (resuming a fade that survived a
+        // reconnect) and re-arms itself while a fade is running; actions that start
+        // a new fade restart it through `State::fade_timer`.
+        {
+            let ctx_f = ctx.clone();
+            let state_f = state.clone();
+            let timer = mainloop.new_timer_event_rt(MonotonicTs::now(), Box::new(move |mut ev| {
+                if let Some(next) = fade_step(&ctx_f, &state_f) {
+                    ev.restart_rt(MonotonicTs::now() + next);
+                }
+            }));
+            state.borrow_mut().fade_timer = timer;
+        }
+
         // Render once, then sleep until something actually happens.
         request_redraw(&ctx, state);
         let outcome = loop {
@@ -603,7 +1638,7 @@ impl Control {
                 IterateResult::Success(_) => {}
                 IterateResult::Quit(_) | IterateResult::Err(_) => break SessionEnd::Lost,
             }
-            if quit.get() {
+            if quit.get() || state.borrow().output_closed {
                 break SessionEnd::Eof;
             }
             // A server restart leaves iterate() returning Success but the context
@@ -614,9 +1649,13 @@ impl Control {
             }
         };
 
-        // Keep the event sources alive for the whole session.
+        // Keep the event sources alive for the whole session. The fade timer must
+        // go before its mainloop does.
         drop(stdin_ev);
         drop(bt_ev);
+        drop(sig_ev);
+        drop(cmd_ev);
+        state.borrow_mut().fade_timer = None;
         outcome
     }
 }
@@ -629,38 +1668,204 @@ enum SessionEnd {
     Lost,
 }
 
+/// A user-level operation, bound to a click or sent as a text command on stdin.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    OpenApp,
+    ToggleMute,
+    /// Cycle compact, device name and detailed display.
+    CycleDisplay,
+    VolumeUp,
+    VolumeDown,
+    /// Start the sleep timer with the configured length, or cancel a running one.
+    ToggleSleepTimer,
+    /// Start the sleep timer with the given length in minutes (0 cancels it).
+    SleepTimer(u32),
+    /// Connect the favourite Bluetooth headset, or disconnect it if connected.
+    ToggleBluetooth,
+    /// Control the shown MPRIS player.
+    MediaPlayPause,
+    MediaNext,
+    MediaPrevious,
+    /// Set the volume to a percentage.
+    SetVolume(u16),
+    /// Make the next sink the default.
+    NextSink,
+    Refresh,
+}
+
+/// Map an i3bar click to its action. Shift + middle click toggles the sleep
+/// timer. With `favourite` (`BT_FAVOURITE`), shift + left click
+/// connects/disconnects the favourite headset. With `media` (`SHOW_MEDIA`),
+/// shift + right click plays/pauses the media player, shift + wheel and the
+/// back/forward buttons skip tracks.
+fn click_action(click: &Click, favourite: bool, media: bool) -> Action {
+    let shift = click.modifiers.as_ref().is_some_and(|m| m.iter().any(|m| m == "Shift"));
+    match (click.button, shift) {
+        (1, true) if favourite => Action::ToggleBluetooth,
+        (1, _) => Action::OpenApp,
+        (2, true) => Action::ToggleSleepTimer,
+        (2, false) => Action::ToggleMute,
+        (3, true) if media => Action::MediaPlayPause,
+        (4, true) | (9, _) if media => Action::MediaNext,
+        (5, true) | (8, _) if media => Action::MediaPrevious,
+        (3, _) => Action::CycleDisplay,
+        (4, _) => Action::VolumeUp,
+        (5, _) => Action::VolumeDown,
+        _ => Action::Refresh,
+    }
+}
+
+/// Parse a plain-text command line: `up`, `down`, `mute`, `sleep`,
+/// `sleep <minutes>`, `sleep off`, `bluetooth`, `display`, `play-pause`, `next`,
+/// `previous`, `volume <percent>` or `next-sink`.
+fn parse_command(text: &str) -> Option<Action> {
+    let mut words = text.split_whitespace();
+    let action = match (words.next()?, words.next()) {
+        ("up", None) => Action::VolumeUp,
+        ("down", None) => Action::VolumeDown,
+        ("mute", None) => Action::ToggleMute,
+        ("refresh", None) => Action::Refresh,
+        ("sleep", None) => Action::ToggleSleepTimer,
+        ("sleep", Some("off")) => Action::SleepTimer(0),
+        ("sleep", Some(n)) => Action::SleepTimer(n.parse().ok()?),
+        ("bluetooth", None) => Action::ToggleBluetooth,
+        ("display", None) => Action::CycleDisplay,
+        ("play-pause", None) => Action::MediaPlayPause,
+        ("next", None) => Action::MediaNext,
+        ("previous", None) => Action::MediaPrevious,
+        ("volume", Some(n)) => Action::SetVolume(n.trim_end_matches('%').parse().ok().filter(|&n| n <= MAX_SET_VOLUME)?),
+        ("next-sink", None) => Action::NextSink,
+        _ => return None,
+    };
+    if words.next().is_some() {
+        return None;
+    }
+    Some(action)
+}
+
 /// Handle one line of i3bar click JSON. Lines that don't parse as a click are
-/// treated as a generic refresh request (matches the prior behaviour). Volume and
-/// mute actions are fire-and-forget; the resulting sink-change event drives the
-/// redraw, while local-only changes (device-name toggle) redraw directly.
+/// tried as a text command, and otherwise treated as a generic refresh request
+/// (matches the prior behaviour). Volume and mute actions are fire-and-forget;
+/// the resulting sink-change event drives the redraw, while local-only changes
+/// (device-name toggle) redraw directly.
 fn handle_click(text: &str, ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, volume_app: &str, delta: i32) {
     if text.is_empty() {
         return;
     }
-    let button = match parse_click(text) {
-        Ok(click) => click.button,
-        Err(_) => { request_redraw(ctx, state); return; }
+    // Any interaction acknowledges a guarded spike.
+    if std::mem::take(&mut state.borrow_mut().spike_guarded) {
+        request_redraw(ctx, state);
+    }
+    let action = match parse_click(text) {
+        Ok(click) => {
+            let s = state.borrow();
+            click_action(&click, s.bt_favourite.is_some(), s.media_players.is_some())
+        }
+        Err(_) => parse_command(text).unwrap_or(Action::Refresh),
     };
-    match button {
-        1 => {
+    match action {
+        Action::OpenApp => {
             if let Err(e) = Command::new(volume_app).spawn() {
-                eprintln!("Error spawning volume app: {}", e);
+                error!("Error spawning volume app: {}", e);
             }
         }
-        2 => set_mute_toggle(ctx, state),
-        3 => {
+        Action::ToggleMute => set_mute_toggle(ctx, state),
+        Action::CycleDisplay => {
             {
                 let mut s = state.borrow_mut();
-                s.show_device_name = !s.show_device_name;
+                s.display = s.display.next();
             }
             request_redraw(ctx, state);
         }
-        4 => adjust_volume(ctx, state, delta),
-        5 => adjust_volume(ctx, state, -delta),
-        _ => request_redraw(ctx, state),
+        Action::VolumeUp => adjust_volume(ctx, state, delta),
+        Action::VolumeDown => adjust_volume(ctx, state, -delta),
+        Action::ToggleSleepTimer => {
+            let running = state.borrow().fade.as_ref().is_some_and(|f| f.kind == FadeKind::Sleep);
+            let minutes = if running { 0 } else { state.borrow().sleep_timer_minutes };
+            set_sleep_timer(ctx, state, minutes);
+        }
+        Action::SleepTimer(minutes) => set_sleep_timer(ctx, state, minutes),
+        Action::ToggleBluetooth => toggle_bluetooth(state),
+        Action::MediaPlayPause => media_command(state, "PlayPause"),
+        Action::MediaNext => media_command(state, "Next"),
+        Action::MediaPrevious => media_command(state, "Previous"),
+        Action::SetVolume(pct) => set_volume(ctx, state, pct),
+        Action::NextSink => next_sink(ctx, state),
+        Action::Refresh => request_redraw(ctx, state),
+    }
+}
+
+/// Connect or disconnect the favourite headset on a helper thread (the BlueZ
+/// call blocks for seconds), showing progress and the outcome in the block for
+/// `BT_STATUS_SECS`. Ignored while a previous toggle is still showing.
+fn toggle_bluetooth(state: &Rc<RefCell<State>>) {
+    let s = state.borrow();
+    let mac = match &s.bt_favourite {
+        Some(mac) => mac.clone(),
+        None => {
+            warn!("No BT_FAVOURITE configured");
+            return;
+        }
+    };
+    let status = s.bt_status.clone();
+    let wake_fd = s.wake_fd;
+    {
+        let mut current = status.lock().unwrap();
+        if current.is_some() {
+            return;
+        }
+        *current = Some(format!("{}…", CHAR_HEADSET));
+    }
+    wake_event_loop(wake_fd);
+
+    let spawned = thread::Builder::new().name("bt-connect".to_string()).spawn(move || {
+        let set_status = |text: String| {
+            *status.lock().unwrap() = Some(text);
+            wake_event_loop(wake_fd);
+        };
+        let result = system_bus()
+            .ok_or_else(|| zbus::Error::Failure("no system bus".into()))
+            .and_then(|conn| bluez::toggle_connection(&conn, &mac, |connecting| {
+                set_status(format!("{} {}", CHAR_HEADSET, if connecting { "connecting…" } else { "disconnecting…" }));
+            }));
+        set_status(match result {
+            Ok(true) => format!("{} connected", CHAR_HEADSET),
+            Ok(false) => format!("{} disconnected", CHAR_HEADSET),
+            Err(e) => format!("{} failed: {}", CHAR_HEADSET, bluez::error_text(&e)),
+        });
+        thread::sleep(Duration::from_secs(BT_STATUS_SECS));
+        *status.lock().unwrap() = None;
+        wake_event_loop(wake_fd);
+    });
+    if let Err(e) = spawned {
+        error!("Error spawning bt-connect thread: {}", e);
+        *s.bt_status.lock().unwrap() = None;
+    }
+}
+
+/// Call an MPRIS method (`PlayPause`, `Next`, `Previous`) on the shown player,
+/// off the event loop. The player's signals redraw the block.
+fn media_command(state: &Rc<RefCell<State>>, method: &'static str) {
+    let bus_name = match state.borrow().media_player.clone() {
+        Some(b) => b,
+        None => return,
+    };
+    let spawned = thread::Builder::new().name("mpris-command".to_string()).spawn(move || {
+        if let Err(e) = mpris::send(&bus_name, method) {
+            error!("Error calling {} on {}: {}", method, bus_name, e);
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Error spawning mpris-command thread: {}", e);
     }
 }
 
+/// Wake the event loop through the write end of a wakeup pipe.
+fn wake_event_loop(pipe_wr: RawFd) {
+    let _ = unsafe { libc::write(pipe_wr, [1u8].as_ptr() as *const libc::c_void, 1) };
+}
+
 /// Create a pipe, returning (read_fd, write_fd).
 fn make_pipe() -> Result<(RawFd, RawFd), Box<dyn Error>> {
     let mut fds = [0 as libc::c_int; 2];
@@ -675,38 +1880,221 @@ fn adjust_volume(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, delta_p
     if delta_pct == 0 {
         return;
     }
-    let (name, mut cv) = {
+    let (name, from, mut cv, step_pct, max_pct, fade_duration) = {
         let s = state.borrow();
-        match &s.cur_sink_name {
-            Some(n) => (n.clone(), s.cur_volume),
+        let name = match &s.cur_sink_name {
+            Some(n) => n.clone(),
             None => return,
-        }
+        };
+        let rule = s.rules.for_sink(&name);
+        let step_pct = rule.and_then(|r| r.step).map_or(delta_pct.unsigned_abs(), u32::from);
+        let max_pct = rule.and_then(|r| r.max);
+        // Keep stepping from where a running volume ramp is heading, so quick
+        // scrolling accumulates instead of restarting from a stale level.
+        let (from, base) = match s.fade.as_ref().filter(|f| f.sink_name == name && f.kind == FadeKind::Volume) {
+            Some(f) => (f.volume_at(Instant::now()), f.to),
+            None => (s.cur_volume, s.cur_volume),
+        };
+        (name, from, base, step_pct, max_pct, s.fade_duration)
     };
-    let step = Volume((Volume::NORMAL.0 as f64 * (delta_pct.unsigned_abs() as f64 / 100.0)) as u32);
+    let step = Volume((Volume::NORMAL.0 as f64 * (step_pct as f64 / 100.0)) as u32);
     if delta_pct > 0 {
         cv.increase(step);
     } else {
         cv.decrease(step);
     }
-    // Fire-and-forget: the resulting sink change event triggers a redraw.
-    ctx.borrow().introspect().set_sink_volume_by_name(&name, &cv, None);
+    if let Some(max) = max_pct {
+        rules::cap_volume(&mut cv, max);
+    }
+    apply_volume(ctx, state, name, from, cv, fade_duration);
 }
 
-/// Toggle mute on the current sink.
-fn set_mute_toggle(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) {
-    let (name, mute) = {
+/// Set the current sink to `pct` percent (capped by its rule's maximum and
+/// [`MAX_SET_VOLUME`]).
+fn set_volume(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, pct: u16) {
+    let (name, from, max_pct, fade_duration) = {
         let s = state.borrow();
-        match &s.cur_sink_name {
-            Some(n) => (n.clone(), !s.cur_mute),
+        let name = match &s.cur_sink_name {
+            Some(n) => n.clone(),
             None => return,
-        }
+        };
+        let from = match s.fade.as_ref().filter(|f| f.sink_name == name && f.kind == FadeKind::Volume) {
+            Some(f) => f.volume_at(Instant::now()),
+            None => s.cur_volume,
+        };
+        let max_pct = s.rules.for_sink(&name).and_then(|r| r.max);
+        (name, from, max_pct, s.fade_duration)
+    };
+    let mut cv = rules::volume_at_percent(&from, pct.min(MAX_SET_VOLUME));
+    if let Some(max) = max_pct {
+        rules::cap_volume(&mut cv, max);
+    }
+    apply_volume(ctx, state, name, from, cv, fade_duration);
+}
+
+/// Move sink `name` from volume `from` to `cv`, at once or as a ramp. A running
+/// sleep timer on the sink keeps its deadline and fades on from `cv` instead.
+fn apply_volume(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, name: String, from: ChannelVolumes, cv: ChannelVolumes, fade_duration: Duration) {
+    let sleep = state.borrow().fade.clone().filter(|f| f.kind == FadeKind::Sleep && f.sink_name == name);
+    if let Some(f) = sleep {
+        start_fade(state, f.restarted_from(cv, Instant::now()));
+        return;
+    }
+    if fade_duration.is_zero() {
+        {
+            let mut s = state.borrow_mut();
+            s.fade = None;
+            s.own_volume_change = Some(Instant::now());
+        }
+        // Fire-and-forget: the resulting sink change event triggers a redraw.
+        ctx.borrow().introspect().set_sink_volume_by_name(&name, &cv, None);
+    } else {
+        start_fade(state, Fade::new(FadeKind::Volume, name, from, cv, fade_duration));
+    }
+}
+
+/// Make the sink after the displayed one (in server order) the default.
+fn next_sink(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) {
+    let current = state.borrow().cur_sink_name.clone();
+    let names: Rc<RefCell<Vec<String>>> = Rc::new(RefCell::new(Vec::new()));
+    let ctx_c = ctx.clone();
+    ctx.borrow().introspect().get_sink_info_list(move |res| match res {
+        ListResult::Item(info) => names.borrow_mut().extend(info.name.as_ref().map(|n| n.to_string())),
+        ListResult::End => {
+            if let Some(next) = next_in_cycle(&names.borrow(), current.as_deref()) {
+                info!("switching default sink to {}", next);
+                ctx_c.borrow_mut().set_default_sink(next, |_| {});
+            }
+        }
+        ListResult::Error => warn!("Failed to list sinks for next-sink"),
+    });
+}
+
+/// The name after `current` in `names`, wrapping around (the first one if
+/// `current` isn't among them). `None` if there's nothing to switch to.
+fn next_in_cycle<'a>(names: &'a [String], current: Option<&str>) -> Option<&'a str> {
+    let next = match names.iter().position(|n| Some(n.as_str()) == current) {
+        Some(i) => names.get((i + 1) % names.len()),
+        None => names.first(),
+    }?;
+    (Some(next.as_str()) != current).then_some(next.as_str())
+}
+
+/// Toggle mute on the current sink. With fades enabled, muting ramps down first
+/// and unmuting ramps up from silence; toggling during a mute ramp reverses it.
+fn set_mute_toggle(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) {
+    let (name, mute, level, fade_duration, running) = {
+        let s = state.borrow();
+        match &s.cur_sink_name {
+            Some(n) => {
+                let running = s.fade.clone().filter(|f| &f.sink_name == n);
+                // The level to ramp from/to: where a running ramp is heading, else the current one.
+                let level = match &running {
+                    Some(f) if f.mutes_at_end() => f.from,
+                    Some(f) => f.to,
+                    None => s.cur_volume,
+                };
+                (n.clone(), !s.cur_mute, level, s.fade_duration, running)
+            }
+            None => return,
+        }
+    };
+    if let Some(f) = running.filter(|f| f.mutes_at_end()) {
+        let now = f.volume_at(Instant::now());
+        start_fade(state, Fade::new(FadeKind::Volume, name, now, f.from, fade_duration));
+        if f.kind == FadeKind::Sleep {
+            request_redraw(ctx, state);
+        }
+        return;
+    }
+    if fade_duration.is_zero() {
+        state.borrow_mut().fade = None;
+        ctx.borrow().introspect().set_sink_mute_by_name(&name, mute, None);
+    } else if mute {
+        start_fade(state, Fade::new(FadeKind::Mute, name, level, fade::silenced(&level), fade_duration));
+    } else {
+        let silent = fade::silenced(&level);
+        {
+            let mut introspect = ctx.borrow().introspect();
+            introspect.set_sink_volume_by_name(&name, &silent, None);
+            introspect.set_sink_mute_by_name(&name, false, None);
+        }
+        start_fade(state, Fade::new(FadeKind::Unmute, name, silent, level, fade_duration));
+    }
+}
+
+/// Start (or, with `minutes == 0`, cancel) the sleep timer: fade the current sink
+/// to silence over `minutes`, then mute it and put the original volume back.
+fn set_sleep_timer(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, minutes: u32) {
+    let (name, level, fade_duration, running) = {
+        let s = state.borrow();
+        let running = s.fade.clone().filter(|f| f.kind == FadeKind::Sleep);
+        let name = match (&running, &s.cur_sink_name) {
+            (Some(f), _) => f.sink_name.clone(),
+            (None, Some(n)) => n.clone(),
+            (None, None) => return,
+        };
+        let level = running.as_ref().map_or(s.cur_volume, |f| f.from);
+        (name, level, s.fade_duration, running)
     };
-    ctx.borrow().introspect().set_sink_mute_by_name(&name, mute, None);
+    if minutes == 0 {
+        match running {
+            // Bring the volume back to where the timer started.
+            Some(f) => {
+                let now = f.volume_at(Instant::now());
+                start_fade(state, Fade::new(FadeKind::Volume, name, now, f.from, fade_duration));
+            }
+            None => return,
+        }
+    } else {
+        let duration = Duration::from_secs(u64::from(minutes) * 60);
+        start_fade(state, Fade::new(FadeKind::Sleep, name, level, fade::silenced(&level), duration));
+    }
+    // The sleep indicator changes even if the volume doesn't (yet).
+    request_redraw(ctx, state);
+}
+
+/// Replace any running fade with `fade` and step it right away.
+fn start_fade(state: &Rc<RefCell<State>>, fade: Fade) {
+    let mut s = state.borrow_mut();
+    s.fade = Some(fade);
+    if let Some(timer) = s.fade_timer.as_mut() {
+        timer.restart_rt(MonotonicTs::now());
+    }
+}
+
+/// Apply the current step of the running fade. Returns the delay until the next
+/// step, or `None` once the fade has finished (or when none is running).
+fn fade_step(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) -> Option<Duration> {
+    let mut s = state.borrow_mut();
+    let State { fade: running, own_volume_change, .. } = &mut *s;
+    let fade = running.as_ref()?;
+    let now = Instant::now();
+    *own_volume_change = Some(now);
+    let mut introspect = ctx.borrow().introspect();
+    introspect.set_sink_volume_by_name(&fade.sink_name, &fade.volume_at(now), None);
+    if fade.progress(now) < 1.0 {
+        return Some(fade.step_interval().min(fade.remaining(now)));
+    }
+    if fade.mutes_at_end() {
+        introspect.set_sink_mute_by_name(&fade.sink_name, true, None);
+        introspect.set_sink_volume_by_name(&fade.sink_name, &fade.from, None);
+    }
+    *running = None;
+    None
 }
 
 /// Query the server for the default sink, then the full sink list, and render the
 /// chosen sink. All callbacks run on the event-loop thread.
 fn request_redraw(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) {
+    redraw(ctx, state, true);
+}
+
+/// [`request_redraw`], applying the sink policies (spike guard, speaker
+/// fallback, volume memory) only with `policies`: application streams coming
+/// and going can't change a sink.
+fn redraw(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, policies: bool) {
+    let started = Instant::now();
     let ctx_for_list = ctx.clone();
     let state_for_srv = state.clone();
     // First learn the default sink name, then list sinks (so selection is correct).
@@ -716,17 +2104,212 @@ fn request_redraw(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>) {
 
         let scratch: Rc<RefCell<Vec<(Sink, ChannelVolumes)>>> = Rc::new(RefCell::new(Vec::new()));
         let state_for_end = state_for_srv.clone();
+        let ctx_for_end = ctx_for_list.clone();
         let scratch_cb = scratch.clone();
         ctx_for_list.borrow().introspect().get_sink_info_list(move |res| match res {
             ListResult::Item(info) => {
                 scratch_cb.borrow_mut().push((sink_from_info(info), info.volume));
             }
-            ListResult::End => finalize_render(&state_for_end, &scratch_cb.borrow()),
-            ListResult::Error => {}
+            ListResult::End => {
+                let finish = {
+                    let ctx_end = ctx_for_end.clone();
+                    let state_end = state_for_end.clone();
+                    let scratch_end = scratch_cb.clone();
+                    move |playing: Vec<PlayingApp>| {
+                        state_end.borrow_mut().playing = playing;
+                        let sinks = scratch_end.borrow();
+                        if policies {
+                            guard_volume_spike(&ctx_end, &state_end, &sinks);
+                            apply_fallback_policy(&ctx_end, &state_end, &sinks);
+                            sync_sinks(&ctx_end, &state_end, &sinks);
+                        }
+                        finalize_render(&state_end, &sinks);
+                        debug!("redraw of {} sinks took {:?}", sinks.len(), started.elapsed());
+                    }
+                };
+                if !state_for_end.borrow().needs_sink_inputs() {
+                    finish(Vec::new());
+                    return;
+                }
+                // Then the playing applications, for the detailed view and the media player.
+                let playing: Rc<RefCell<Vec<PlayingApp>>> = Rc::new(RefCell::new(Vec::new()));
+                let mut finish = Some(finish);
+                ctx_for_end.borrow().introspect().get_sink_input_info_list(move |res| match res {
+                    ListResult::Item(info) => playing.borrow_mut().extend(playing_app_from_info(info)),
+                    ListResult::End | ListResult::Error => {
+                        if let ListResult::Error = res {
+                            warn!("Failed to list sink inputs");
+                        }
+                        if let Some(finish) = finish.take() {
+                            finish(playing.take());
+                        }
+                    }
+                });
+            }
+            ListResult::Error => warn!("Failed to list sinks; keeping the last output"),
         });
     });
 }
 
+/// Undo a sudden external rise of the displayed sink's volume (opt-in via
+/// `SPIKE_GUARD_PERCENT`). Runs on every sink list fetched for a subscribe
+/// event: if the volume jumped by more than the threshold since the last render
+/// and the block didn't cause it, put back the previous level (or the ceiling)
+/// and flag the block urgent.
+fn guard_volume_spike(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)]) {
+    let mut s = state.borrow_mut();
+    let threshold = match s.spike_guard {
+        Some(t) => t,
+        None => return,
+    };
+    if s.own_volume_change.is_some_and(|t| t.elapsed() < OWN_CHANGE_GRACE) {
+        return;
+    }
+    let chosen = {
+        let sink_views: Vec<&Sink> = sinks.iter().map(|(k, _)| k).collect();
+        match choose_sink_idx(&sink_views, s.default_sink.as_deref()) {
+            Some(i) => i,
+            None => return,
+        }
+    };
+    let (sink, volume) = &sinks[chosen];
+    if s.cur_sink_name.as_deref() != Some(sink.sink_name.as_str()) {
+        return;
+    }
+    let target = match spike_reset_level(s.cur_volume_pct, sink.volume_percent, threshold, s.spike_ceiling) {
+        Some(t) => t,
+        None => return,
+    };
+    let cv = if target == s.cur_volume_pct { s.cur_volume } else { rules::volume_at_percent(volume, target) };
+    s.own_volume_change = Some(Instant::now());
+    s.spike_guarded = true;
+    ctx.borrow().introspect().set_sink_volume_by_name(&sink.sink_name, &cv, None);
+}
+
+/// The level (percent) to put back after a rise from `prev` to `new`, or `None`
+/// if the rise is within `threshold`. With a `ceiling`, anything up to it (or up
+/// to `prev`, if that was louder already) is let through.
+fn spike_reset_level(prev: u16, new: u16, threshold: u16, ceiling: Option<u16>) -> Option<u16> {
+    if new <= prev.saturating_add(threshold) {
+        return None;
+    }
+    let target = ceiling.map_or(prev, |c| c.max(prev)).min(new);
+    (target < new).then_some(target)
+}
+
+/// Apply the speaker fallback policy: when the displayed or the default sink
+/// stops being a headphone-type device (headset dropped, headphones unplugged
+/// from a jack), mute or cap the sink that took over.
+fn apply_fallback_policy(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)]) {
+    let mut s = state.borrow_mut();
+    let State { default_sink, prev_displayed, prev_default, fallback_policy, .. } = &mut *s;
+    let target = track_fallback(sinks, default_sink.as_deref(), prev_displayed, prev_default);
+    let Some((sink, volume)) = target else { return };
+    match fallback_action(*fallback_policy, sink, volume) {
+        Some(FallbackAction::Mute) => {
+            ctx.borrow().introspect().set_sink_mute_by_name(&sink.sink_name, true, None);
+        }
+        Some(FallbackAction::SetVolume(cv)) => {
+            s.own_volume_change = Some(Instant::now());
+            ctx.borrow().introspect().set_sink_volume_by_name(&sink.sink_name, &cv, None);
+        }
+        None => {}
+    }
+}
+
+/// The sink that just took over from headphones, if any, given the displayed
+/// and default sinks (name, headphones) at the previous render, which are
+/// updated to the current ones.
+fn track_fallback<'a>(
+    sinks: &'a [(Sink, ChannelVolumes)],
+    default_sink: Option<&str>,
+    prev_displayed: &mut Option<(String, bool)>,
+    prev_default: &mut Option<(String, bool)>,
+) -> Option<&'a (Sink, ChannelVolumes)> {
+    let displayed = {
+        let sink_views: Vec<&Sink> = sinks.iter().map(|(k, _)| k).collect();
+        choose_sink_idx(&sink_views, default_sink).map(|i| &sinks[i])
+    };
+    let default = default_sink.and_then(|d| sinks.iter().find(|(k, _)| k.sink_name == d));
+    // Only a headphone sink that went away (or switched to a speaker port) counts,
+    // not one that merely stopped playing while another sink was running.
+    let headphones_gone = |prev: &Option<(String, bool)>| match prev {
+        Some((name, true)) => !sinks.iter().any(|(k, _)| &k.sink_name == name && k.headphones),
+        _ => false,
+    };
+    let fell_back = |prev: &Option<(String, bool)>, now: Option<&'a (Sink, ChannelVolumes)>| {
+        now.filter(|(k, _)| !k.headphones && headphones_gone(prev))
+    };
+    let target = fell_back(prev_displayed, displayed).or_else(|| fell_back(prev_default, default));
+    *prev_displayed = displayed.map(|(k, _)| (k.sink_name.clone(), k.headphones));
+    *prev_default = default.map(|(k, _)| (k.sink_name.clone(), k.headphones));
+    target
+}
+
+/// What the speaker fallback does to the sink that took over.
+#[derive(Debug)]
+enum FallbackAction {
+    Mute,
+    SetVolume(ChannelVolumes),
+}
+
+fn fallback_action(policy: FallbackPolicy, sink: &Sink, volume: &ChannelVolumes) -> Option<FallbackAction> {
+    match policy {
+        FallbackPolicy::None => None,
+        FallbackPolicy::Mute => (!sink.mute).then_some(FallbackAction::Mute),
+        FallbackPolicy::Cap(cap) => (sink.volume_percent > cap).then(|| {
+            let mut cv = *volume;
+            rules::cap_volume(&mut cv, cap);
+            FallbackAction::SetVolume(cv)
+        }),
+    }
+}
+
+/// Set up sinks that just (re)appeared: put back their remembered volume and
+/// mute state, or else apply their rule's default volume (capped by the rule's
+/// maximum either way). Then record every listed sink in the volume memory.
+/// The first list after startup only records.
+fn sync_sinks(ctx: &Rc<RefCell<Context>>, state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)]) {
+    let mut s = state.borrow_mut();
+    let State { volume_memory, known_sinks, rules, own_volume_change, .. } = &mut *s;
+    let previous = known_sinks.replace(sinks.iter().map(|(k, _)| k.sink_name.clone()).collect());
+
+    let mut changed = false;
+    for (sink, volume) in sinks {
+        let name = &sink.sink_name;
+        let memory = volume_memory.as_mut().filter(|m| !m.is_excluded(name));
+        let reappeared = previous.as_ref().is_some_and(|p| !p.contains(name));
+        if reappeared {
+            let rule = rules.for_sink(name);
+            let restore = match memory.as_ref().and_then(|m| m.get(name)) {
+                Some(saved) => Some((saved.apply_to(volume), Some(saved.mute))),
+                None => rule.and_then(|r| r.default).map(|pct| (rules::volume_at_percent(volume, pct), None)),
+            };
+            if let Some((mut cv, mute)) = restore {
+                if let Some(max) = rule.and_then(|r| r.max) {
+                    rules::cap_volume(&mut cv, max);
+                }
+                // The resulting sink change event records the restored values.
+                *own_volume_change = Some(Instant::now());
+                let mut introspect = ctx.borrow().introspect();
+                introspect.set_sink_volume_by_name(name, &cv, None);
+                if let Some(mute) = mute {
+                    introspect.set_sink_mute_by_name(name, mute, None);
+                }
+                continue;
+            }
+        }
+        if let Some(m) = memory {
+            changed |= m.record(name, SavedVolume::new(volume, sink.mute));
+        }
+    }
+    if changed {
+        if let Some(m) = volume_memory.as_mut() {
+            m.save_later();
+        }
+    }
+}
+
 /// Select the sink to show, update shared state, and print the i3bar line if it changed.
 fn finalize_render(state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)]) {
     let mut s = state.borrow_mut();
@@ -740,46 +2323,117 @@ fn finalize_render(state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)])
     };
     let (sink, volume) = &sinks[chosen];
 
-    // Bluetooth battery (cached only; warmed off-loop by the poller thread).
-    let mac = if sink.sink_name.starts_with("bluez_output.") {
-        mac_from_sink_name(&sink.sink_name)
+    // Device battery (cached only; warmed off-loop by the poller thread).
+    let target = battery_target(sink);
+    let battery = if s.show_bt_battery {
+        target.as_ref().and_then(|t| displayed_battery(&t.key))
     } else {
         None
     };
-    let bt_battery = if s.show_bt_battery {
-        mac.as_deref().and_then(cached_bt_battery)
-    } else {
-        None
+    let target_changed = {
+        let mut current = s.battery_target.lock().unwrap();
+        let changed = *current != target;
+        *current = target;
+        changed
     };
-    *s.current_bluez_mac.lock().unwrap() = mac;
+    let (battery, battery_stale) = battery.map_or((None, false), |(b, stale)| (Some(b), stale));
+    let (battery_eta, battery_charged) = match (&battery, s.battery_target.lock().unwrap().as_ref()) {
+        (Some(_), Some(t)) if !battery_stale => {
+            let key = t.key.to_uppercase();
+            let now = unix_now();
+            let history = BATTERY_HISTORY.lock().unwrap();
+            (history.eta(&key, now), history.last_charged(&key).map(|at| Duration::from_secs(now.saturating_sub(at))))
+        }
+        _ => (None, None),
+    };
+    if target_changed && (battery.is_none() || battery_stale) {
+        if let Some(kick) = &s.bt_kick {
+            let _ = kick.send(());
+        }
+    }
 
     // Remember the current sink so click actions can act on it.
     s.cur_sink_name = Some(sink.sink_name.clone());
     s.cur_volume = *volume;
+    s.cur_volume_pct = sink.volume_percent;
     s.cur_mute = sink.mute;
 
-    let include_name = s.show_device_name;
-    match render_sink_output(sink, include_name, bt_battery) {
-        Ok((line, vol_pct)) => {
+    let bt_status = s.bt_status.lock().unwrap().clone();
+    let media = s.media_players.as_ref().and_then(|players| {
+        let players = players.lock().unwrap();
+        let pids: Vec<u32> = s.playing.iter().filter(|p| p.sink == sink.index).filter_map(|p| p.pid).collect();
+        mpris::choose_player(&players, &pids).map(|p| (p.bus_name.clone(), p.label()))
+    });
+    let (media_player, media) = media.map_or((None, None), |(bus, label)| (Some(bus), label));
+    s.media_player = media_player;
+    let low_quality = sink.codec.as_ref()
+        .is_some_and(|c| s.low_quality_codecs.contains(&c.to_ascii_lowercase()));
+    let opts = RenderOptions {
+        format: &s.format,
+        include_device_name: s.display == DisplayMode::DeviceName,
+        detailed: s.display == DisplayMode::Detailed,
+        playing: s.playing.iter().filter(|p| p.sink == sink.index).map(|p| p.name.clone()).collect(),
+        default_sink: s.default_sink.as_deref(),
+        battery,
+        battery_stale,
+        battery_eta,
+        battery_charged,
+        battery_icons: Some(&s.battery_icons),
+        battery_color: s.battery_color.as_deref(),
+        battery_low_color: s.battery_low_color.as_deref(),
+        sleep_remaining: s.fade.as_ref()
+            .filter(|f| f.kind == FadeKind::Sleep)
+            .map(|f| f.remaining(Instant::now())),
+        max_volume: s.rules.for_sink(&sink.sink_name).and_then(|r| r.max),
+        urgent: s.spike_guarded,
+        low_quality_color: low_quality.then_some(s.low_quality_color.as_str()),
+        status: bt_status.as_deref(),
+        media,
+    };
+    // Scripts get updates even while the bar is hidden.
+    if let Some(service) = &s.service {
+        let _ = service.send(service::Snapshot {
+            volume: sink.volume_percent,
+            muted: sink.mute,
+            sink_name: sink.sink_name.clone(),
+            battery: opts.battery.as_ref().and_then(|b| b.lowest()).map(|l| l.percentage),
+        });
+    }
+    if s.paused.load(Ordering::Relaxed) {
+        return;
+    }
+    let (block, vol_pct) = render_block(sink, &opts);
+    match s.backend.render(&block, false) {
+        Ok(line) => {
             if line != s.previous_line || s.first_update {
+                let framed = if s.first_update { s.backend.render(&block, true).unwrap_or_else(|_| line.clone()) } else { line.clone() };
                 let mut out = io::stdout().lock();
-                if writeln!(out, "{}", line).is_err() || out.flush().is_err() {
+                if writeln!(out, "{}", framed).is_err() || out.flush().is_err() {
+                    // Without stdin to signal EOF, a closed stdout is how we learn the bar is gone.
+                    if !s.backend.reads_stdin() {
+                        s.output_closed = true;
+                    }
                     return;
                 }
                 s.previous_line = line;
             }
             if s.last_volume != vol_pct && !s.first_update {
-                if let Some(w) = s.wob_stdin.as_mut() {
-                    if write!(w, "{}\n", vol_pct).is_err() || w.flush().is_err() {
-                        eprintln!("Error writing to wob, disabling wob output.");
-                        s.wob_stdin = None;
-                    }
+                if let Some(w) = s.wob.as_mut() {
+                    w.show(sink.volume_percent, sink.mute);
                 }
             }
+            if let Some(osd) = &s.osd {
+                let _ = osd.send(notify::VolumeOsd {
+                    sink: sink.sink_name.clone(),
+                    device: sink.device_name.clone(),
+                    volume: sink.volume_percent,
+                    muted: sink.mute,
+                });
+            }
             s.last_volume = vol_pct;
             s.first_update = false;
         }
-        Err(e) => eprintln!("Error rendering output: {}", e),
+        Err(e) => error!("Error rendering output: {}", e),
     }
 }
 
@@ -787,7 +2441,105 @@ fn finalize_render(state: &Rc<RefCell<State>>, sinks: &[(Sink, ChannelVolumes)])
 fn choose_sink_idx(sinks: &[&Sink], default_sink_node: Option<&str>) -> Option<usize> {
     sinks.iter().position(|s| s.active)
         .or_else(|| default_sink_node.and_then(|d| sinks.iter().position(|s| s.sink_name == d)))
-        .or_else(|| if sinks.is_empty() { None } else { Some(0) })
+        .or(if sinks.is_empty() { None } else { Some(0) })
+}
+
+/// Read ends of the pipes that feed the event loop from other threads and
+/// signal handlers. They outlive the server connection, so every session
+/// watches the same ones.
+#[derive(Clone, Copy)]
+struct EventFds {
+    /// Battery threads: redraw.
+    battery: RawFd,
+    /// Signal numbers from [`on_signal`].
+    signals: RawFd,
+    /// Text commands from the command socket, one per line.
+    commands: RawFd,
+}
+
+/// Read everything available from the non-blocking `fd` into `acc` and return
+/// the complete lines, plus whether the writer has gone (EOF).
+fn read_lines(fd: RawFd, acc: &mut Vec<u8>) -> (Vec<String>, bool) {
+    let mut buf = [0u8; 1024];
+    let eof = loop {
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n > 0 {
+            acc.extend_from_slice(&buf[..n as usize]);
+        } else {
+            break n == 0; // EOF or EAGAIN
+        }
+    };
+    let mut lines = Vec::new();
+    while let Some(pos) = acc.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = acc.drain(..=pos).collect();
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    (lines, eof)
+}
+
+/// Whether another process accepts connections on the Unix socket at `path`.
+fn socket_in_use(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+/// Listen on a Unix socket at `path` for text commands and forward each line
+/// to the event loop through `pipe_wr`, e.g. `echo up | socat - UNIX-CONNECT:<path>`.
+fn spawn_command_socket(path: PathBuf, pipe_wr: RawFd) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // A socket left behind by a previous run would make bind fail (a live one
+    // is left alone, see `socket_in_use`).
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .map_err(|e| format!("Failed to bind command socket {}: {}", path.display(), e))?;
+    thread::Builder::new().name("command-socket".to_string()).spawn(move || {
+        for stream in listener.incoming().flatten() {
+            for line in io::BufReader::new(stream).lines().map_while(Result::ok) {
+                write_command(pipe_wr, line.trim());
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Queue a text command for the event loop on the command pipe. Lines are far
+/// below `PIPE_BUF`, so writes from several threads don't interleave.
+fn write_command(pipe_wr: RawFd, command: &str) {
+    let line = format!("{}\n", command);
+    let _ = unsafe { libc::write(pipe_wr, line.as_ptr() as *const libc::c_void, line.len()) };
+}
+
+/// Write end of the signal self-pipe, read by the async-signal-safe handler.
+static SIGNAL_PIPE_WR: AtomicI32 = AtomicI32::new(-1);
+
+/// Forward a signal to the event loop by writing its number to the signal pipe.
+extern "C" fn on_signal(sig: libc::c_int) {
+    let fd = SIGNAL_PIPE_WR.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = sig as u8;
+        let _ = unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+}
+
+/// Route `signals` into a new self-pipe and return its (non-blocking) read end.
+fn install_signal_pipe(signals: &[libc::c_int]) -> Result<RawFd, Box<dyn Error>> {
+    let (rd, wr) = make_pipe()?;
+    set_nonblocking(rd);
+    set_nonblocking(wr);
+    SIGNAL_PIPE_WR.store(wr, Ordering::Relaxed);
+    for &sig in signals {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(sig, &action, std::ptr::null_mut()) != 0 {
+                return Err(format!("failed to install handler for signal {}", sig).into());
+            }
+        }
+    }
+    Ok(rd)
 }
 
 /// Put a file descriptor into non-blocking mode (best-effort).
@@ -917,9 +2669,10 @@ mod test {
             ..Default::default()
         };
 
-        let (json, vol) = render_sink_output(&s, true, Some(30)).unwrap();
+        let opts = RenderOptions { include_device_name: true, battery: Some(Battery::single(30, false)), ..Default::default() };
+        let (json, vol) = render_sink_output(&s, &opts).unwrap();
         assert!(json.contains("60%"));
-        assert!(json.contains("🔋30%"));
+        assert!(json.contains("🪫30%"));
         assert!(json.contains("ACME Headphones"));
         assert_eq!(vol, 60);
     }
@@ -929,18 +2682,90 @@ mod test {
         let mac = "aa:bb:cc:dd:ee:ff";
         {
             let mut guard = BT_BATTERY_CACHE.lock().unwrap();
-            guard.insert(mac.to_uppercase(), (Instant::now(), 77));
+            guard.insert(mac.to_uppercase(), CachedBattery { read_at: Instant::now(), battery: Battery::single(77, false), notified: None, stale: false });
         }
-        assert_eq!(cached_bt_battery(mac), Some(77));
+        assert_eq!(cached_bt_battery(mac), Some(Battery::single(77, false)));
 
         // expired entry should not be returned
         {
             let mut guard = BT_BATTERY_CACHE.lock().unwrap();
-            guard.insert(mac.to_uppercase(), (Instant::now() - Duration::from_secs(BT_BATTERY_TTL_SECS + 1), 88));
+            guard.insert(mac.to_uppercase(), CachedBattery {
+                read_at: Instant::now() - BATTERY_SETTINGS.read().unwrap().ttl - Duration::from_secs(1),
+                battery: Battery::single(88, false),
+                notified: None,
+                stale: false,
+            });
         }
         assert_eq!(cached_bt_battery(mac), None);
     }
 
+    #[test]
+    fn render_colored_battery_segment() {
+        let s = Sink { volume_percent: 50, device_name: "Tom & Jerry".into(), ..Default::default() };
+        let opts = RenderOptions {
+            include_device_name: true,
+            battery: Some(Battery::single(20, false)),
+            battery_color: Some("#00FF00"),
+            battery_low_color: Some("#FF0000"),
+            ..Default::default()
+        };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["markup"], "pango");
+        assert_eq!(v["full_text"], format!("{} 50% <span foreground=\"#FF0000\">🪫20%</span> [Tom &amp; Jerry]", CHAR_AUDIO_MEDIUM));
+
+        let opts = RenderOptions { battery: Some(Battery::single(20, false)), ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(!json.contains("markup"));
+    }
+
+    #[test]
+    fn render_battery_eta_and_last_charge() {
+        let s = Sink { volume_percent: 70, ..Default::default() };
+        let opts = RenderOptions {
+            format: "{volume}% {battery} {battery_eta} ({battery_charged})",
+            battery: Some(Battery::single(60, false)),
+            battery_eta: Some(Duration::from_secs(3 * 3600 + 600)),
+            battery_charged: Some(Duration::from_secs(2 * 3600)),
+            ..Default::default()
+        };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(json.contains("70% 🔋60% ~3h10m (charged 2h00m ago)"));
+    }
+
+    #[test]
+    fn battery_cache_round_trip_is_stale() {
+        let mac = "0A:0B:0C:0D:0E:0F";
+        let path = std::env::temp_dir().join(format!("i3blocks-volume-pw-test-{}-battery-cache/battery.json", std::process::id()));
+        store_bt_battery(mac, Battery::single(64, false));
+        save_battery_cache(&path).unwrap();
+        BT_BATTERY_CACHE.lock().unwrap().remove(mac);
+        load_battery_cache(&path);
+        assert_eq!(cached_bt_battery(mac), None);
+        assert_eq!(displayed_battery(mac), Some((Battery::single(64, false), true)));
+        store_bt_battery(mac, Battery::single(63, false));
+        assert_eq!(displayed_battery(mac), Some((Battery::single(63, false), false)));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let s = Sink { volume_percent: 50, ..Default::default() };
+        let opts = RenderOptions { battery: Some(Battery::single(64, false)), battery_stale: true, ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(json.contains("🔋64%?"));
+    }
+
+    #[test]
+    fn low_battery_alert_debounces_per_device() {
+        let key = "11:22:33:44:55:66";
+        store_bt_battery(key, Battery::single(15, false));
+        assert_eq!(low_battery_alert(key, &[20, 10]), Some((15, 20)));
+        store_bt_battery(key, Battery::single(14, false));
+        assert_eq!(low_battery_alert(key, &[20, 10]), None);
+        store_bt_battery(key, Battery::single(8, true));
+        assert_eq!(low_battery_alert(key, &[20, 10]), None);
+        store_bt_battery(key, Battery::single(8, false));
+        assert_eq!(low_battery_alert(key, &[20, 10]), Some((8, 10)));
+    }
+
     #[test]
     fn mac_from_sink_name_lowercase() {
         assert_eq!(mac_from_sink_name("bluez_output.00_1a_7d_da_71_13.a2dp-sink"), Some("00:1A:7D:DA:71:13".to_string()));
@@ -952,15 +2777,6 @@ mod test {
         assert_eq!(mac_from_sink_name("bluez_output.001A7DDA7113.a2dp-sink"), None);
     }
 
-    #[test]
-    fn bluez_device_paths_helper() {
-        let mac = "aa:bb:cc:dd:ee:ff";
-        let paths = bluez_device_paths_for_mac(mac, 4);
-        assert_eq!(paths[0], "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF");
-        assert_eq!(paths[1], "/org/bluez/hci1/dev_AA_BB_CC_DD_EE_FF");
-        assert_eq!(paths.len(), 4);
-    }
-
     #[test]
     fn parse_bluetoothctl_info_output_from_fixture() {
         let s = include_str!("../tests/bluetoothctl_info.txt");
@@ -975,16 +2791,16 @@ mod test {
 
     #[test]
     fn choose_sink_prefers_running() {
-        let mut a = Sink::default(); a.sink_name = "a".into();
-        let mut b = Sink::default(); b.sink_name = "b".into(); b.active = true;
+        let a = Sink { sink_name: "a".into(), ..Default::default() };
+        let b = Sink { sink_name: "b".into(), active: true, ..Default::default() };
         let sinks = vec![a, b];
         assert_eq!(choose_sink(&sinks, Some("a")).unwrap().sink_name, "b");
     }
 
     #[test]
     fn choose_sink_falls_back_to_default_then_first() {
-        let mut a = Sink::default(); a.sink_name = "a".into();
-        let mut b = Sink::default(); b.sink_name = "b".into();
+        let a = Sink { sink_name: "a".into(), ..Default::default() };
+        let b = Sink { sink_name: "b".into(), ..Default::default() };
         let sinks = vec![a, b];
         // default match wins over first
         assert_eq!(choose_sink(&sinks, Some("b")).unwrap().sink_name, "b");
@@ -993,4 +2809,347 @@ mod test {
         // empty -> none
         assert!(choose_sink(&[], Some("x")).is_none());
     }
+
+    #[test]
+    fn parse_command_variants() {
+        assert_eq!(parse_command("up"), Some(Action::VolumeUp));
+        assert_eq!(parse_command(" mute "), Some(Action::ToggleMute));
+        assert_eq!(parse_command("sleep"), Some(Action::ToggleSleepTimer));
+        assert_eq!(parse_command("sleep 45"), Some(Action::SleepTimer(45)));
+        assert_eq!(parse_command("sleep off"), Some(Action::SleepTimer(0)));
+        assert_eq!(parse_command("bluetooth"), Some(Action::ToggleBluetooth));
+        assert_eq!(parse_command("sleep soon"), None);
+        assert_eq!(parse_command("up 5"), None);
+        assert_eq!(parse_command(""), None);
+        assert_eq!(parse_command("volume 40%"), Some(Action::SetVolume(40)));
+        assert_eq!(parse_command("volume loud"), None);
+        assert_eq!(parse_command("volume 150"), Some(Action::SetVolume(150)));
+        assert_eq!(parse_command("volume 1000"), None);
+        assert_eq!(parse_command("next-sink"), Some(Action::NextSink));
+        assert_eq!(parse_command("display"), Some(Action::CycleDisplay));
+    }
+
+    #[test]
+    fn next_sink_wraps_around() {
+        let names: Vec<String> = vec!["a".into(), "b".into(), "c".into()];
+        assert_eq!(next_in_cycle(&names, Some("b")), Some("c"));
+        assert_eq!(next_in_cycle(&names, Some("c")), Some("a"));
+        assert_eq!(next_in_cycle(&names, Some("gone")), Some("a"));
+        assert_eq!(next_in_cycle(&names[..1], Some("a")), None);
+        assert_eq!(next_in_cycle(&[], None), None);
+    }
+
+    #[test]
+    fn shift_middle_click_toggles_sleep_timer() {
+        let mut click = parse_click(include_str!("../tests/click.json")).unwrap();
+        click.button = 2;
+        assert_eq!(click_action(&click, false, false), Action::ToggleSleepTimer);
+        click.modifiers = None;
+        assert_eq!(click_action(&click, false, false), Action::ToggleMute);
+    }
+
+    #[test]
+    fn shift_left_click_toggles_bluetooth_with_status() {
+        let mut click = parse_click(include_str!("../tests/click.json")).unwrap();
+        click.button = 1;
+        assert_eq!(click_action(&click, true, false), Action::ToggleBluetooth);
+        assert_eq!(click_action(&click, false, false), Action::OpenApp);
+        click.modifiers = None;
+        assert_eq!(click_action(&click, false, false), Action::OpenApp);
+
+        let s = Sink { volume_percent: 10, ..Default::default() };
+        let opts = RenderOptions { status: Some("🎧 connecting…"), ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["full_text"], format!("{} 10% 🎧 connecting…", CHAR_AUDIO_LOW));
+    }
+
+    #[test]
+    fn default_format_shows_media() {
+        let config = Config::init_from_hashmap(&HashMap::new()).unwrap();
+        let s = Sink { volume_percent: 70, ..Default::default() };
+        let opts = RenderOptions { format: &config.format, media: Some("▶ Daft Punk – Get Lucky".into()), ..Default::default() };
+        let (block, _) = render_block(&s, &opts);
+        assert_eq!(block.full_text, format!("{} 70% ▶ Daft Punk – Get Lucky", CHAR_AUDIO_HIGH));
+    }
+
+    #[test]
+    fn media_clicks_and_text() {
+        let mut click = parse_click(include_str!("../tests/click.json")).unwrap();
+        click.button = 3;
+        assert_eq!(click_action(&click, false, true), Action::MediaPlayPause);
+        assert_eq!(click_action(&click, false, false), Action::CycleDisplay);
+        click.button = 4;
+        assert_eq!(click_action(&click, false, true), Action::MediaNext);
+        assert_eq!(click_action(&click, false, false), Action::VolumeUp);
+        click.modifiers = None;
+        assert_eq!(click_action(&click, false, true), Action::VolumeUp);
+        click.button = 8;
+        assert_eq!(click_action(&click, false, true), Action::MediaPrevious);
+        assert_eq!(click_action(&click, false, false), Action::Refresh);
+        assert_eq!(parse_command("play-pause"), Some(Action::MediaPlayPause));
+
+        let s = Sink { volume_percent: 70, ..Default::default() };
+        let opts = RenderOptions { media: Some("▶ Daft Punk – Get Lucky".into()), ..Default::default() };
+        let (block, _) = render_block(&s, &opts);
+        assert_eq!(block.full_text, format!("{} 70% ▶ Daft Punk – Get Lucky", CHAR_AUDIO_HIGH));
+    }
+
+    #[test]
+    fn render_sleep_timer_indicator() {
+        let s = Sink { volume_percent: 30, ..Default::default() };
+        let opts = RenderOptions { sleep_remaining: Some(Duration::from_secs(61)), ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(json.contains(&format!("{}2m", CHAR_SLEEP_TIMER)));
+    }
+
+    #[test]
+    fn signals_arrive_through_pipe() {
+        let rd = install_signal_pipe(&[STOP_SIGNAL, CONT_SIGNAL]).unwrap();
+        unsafe { libc::raise(CONT_SIGNAL) };
+        let mut buf = [0u8; 8];
+        let n = unsafe { libc::read(rd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        assert_eq!(n, 1);
+        assert_eq!(libc::c_int::from(buf[0]), CONT_SIGNAL);
+    }
+
+    #[test]
+    fn render_waybar_json() {
+        let s = Sink {
+            volume_percent: 120,
+            mute: true,
+            device_name: "WH-1000XM4".into(),
+            sink_name: "bluez_output.AA_BB_CC_DD_EE_FF.1".into(),
+            ..Default::default()
+        };
+        let (block, _) = render_block(&s, &RenderOptions::default());
+        let json = output::from_config("waybar", false, "pavucontrol").unwrap().render(&block, true).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["text"], format!("{} 120%", CHAR_AUDIO_MUTED));
+        assert_eq!(v["alt"], "muted");
+        assert_eq!(v["tooltip"], "WH-1000XM4\nVolume: 120% (muted)");
+        assert_eq!(v["class"], serde_json::json!(["muted", "bluetooth", "over-amplified"]));
+        assert_eq!(v["percentage"], 120);
+        assert!(v.get("full_text").is_none());
+    }
+
+    #[test]
+    fn render_detailed_view() {
+        let s = Sink {
+            volume_percent: 50,
+            device_name: "WH-1000XM4".into(),
+            sink_name: "bluez_output.AA_BB_CC_DD_EE_FF.1".into(),
+            description: Some("WH-1000XM4 (LDAC)".into()),
+            port: Some("Headphones".into()),
+            sample_spec: Some("s24le 2ch 96000Hz".into()),
+            codec: Some("ldac".into()),
+            profile: Some("a2dp-sink".into()),
+            channel_volumes: vec![("FL".into(), 50), ("FR".into(), 48)],
+            ..Default::default()
+        };
+        let mut opts = RenderOptions {
+            battery: Some(Battery::single(80, false)),
+            battery_eta: Some(Duration::from_secs(3 * 3600)),
+            playing: vec!["Firefox".into(), "mpv".into()],
+            default_sink: Some("alsa_output.pci-0000_00_1f.3.analog-stereo"),
+            ..Default::default()
+        };
+        let (block, _) = render_block(&s, &opts);
+        assert_eq!(block.tooltip.as_deref(), Some("WH-1000XM4 (LDAC)\nPort: Headphones\nFormat: s24le 2ch 96000Hz\n\
+            Codec: LDAC (a2dp-sink)\nBattery: 🔋80%, ~3h00m left\nVolume: FL 50% FR 48%\nPlaying: Firefox, mpv\n\
+            Default sink: alsa_output.pci-0000_00_1f.3.analog-stereo"));
+        assert_eq!(block.full_text, format!("{} 50% 🔋80%", CHAR_AUDIO_MEDIUM));
+
+        opts.detailed = true;
+        opts.default_sink = Some("bluez_output.AA_BB_CC_DD_EE_FF.1");
+        let (block, _) = render_block(&s, &opts);
+        assert!(block.full_text.starts_with("WH-1000XM4 (LDAC) | Port: Headphones | "));
+        assert!(block.full_text.ends_with(" | Default sink"));
+        assert_eq!(block.short_text, Some(format!("{} 50% 🔋80%", CHAR_AUDIO_MEDIUM)));
+    }
+
+    #[test]
+    fn display_modes_cycle() {
+        assert_eq!(DisplayMode::Compact.next(), DisplayMode::DeviceName);
+        assert_eq!(DisplayMode::DeviceName.next(), DisplayMode::Detailed);
+        assert_eq!(DisplayMode::Detailed.next(), DisplayMode::Compact);
+    }
+
+    #[test]
+    fn command_socket_forwards_lines() {
+        let (rd, wr) = make_pipe().unwrap();
+        set_nonblocking(rd);
+        let path = std::env::temp_dir().join(format!("i3blocks-volume-pw-test-{}-command-socket/command.sock", std::process::id()));
+        assert!(!socket_in_use(&path));
+        spawn_command_socket(path.clone(), wr).unwrap();
+        assert!(socket_in_use(&path));
+        {
+            let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            client.write_all(b"up\n mute \n").unwrap();
+        }
+        let mut acc = Vec::new();
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines.extend(read_lines(rd, &mut acc).0);
+            if lines.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(lines, vec!["up\n", "mute\n"]);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn signal_commands() {
+        assert_eq!(signal_command(libc::SIGRTMIN() + 3), Some("mute"));
+        assert_eq!(signal_command(libc::SIGUSR1), None);
+    }
+
+    #[test]
+    fn i3bar_framing() {
+        let i3bar = output::from_config("swaybar", false, "pavucontrol").unwrap();
+        let (block, _) = render_block(&Sink { volume_percent: 50, ..Default::default() }, &RenderOptions::default());
+        assert_eq!(i3bar.render(&block, true).unwrap(), format!("[{{\"full_text\":\"{} 50%\"}}]", CHAR_AUDIO_MEDIUM));
+        assert!(i3bar.render(&block, false).unwrap().starts_with(",[{"));
+        assert!(output::from_config("dzen", false, "pavucontrol").is_err());
+
+        assert_eq!(unframe_click_line("["), "");
+        assert_eq!(unframe_click_line("[{\"button\":1}"), "{\"button\":1}");
+        assert_eq!(unframe_click_line(",{\"button\":4}\n"), "{\"button\":4}");
+        assert_eq!(unframe_click_line(" up "), "up");
+        let click = parse_click(unframe_click_line(&format!(",{}", include_str!("../tests/click.json")))).unwrap();
+        assert_eq!(click.button, 1);
+    }
+
+    #[test]
+    fn config_list_splits_and_trims() {
+        assert_eq!(config_list(" a, b ,,c"), vec!["a", "b", "c"]);
+        assert!(config_list("").is_empty());
+    }
+
+    #[test]
+    fn render_urgent_above_rule_max() {
+        let s = Sink { volume_percent: 50, ..Default::default() };
+        let (json, _) = render_sink_output(&s, &RenderOptions::default()).unwrap();
+        assert!(!json.contains("urgent"));
+        let opts = RenderOptions { max_volume: Some(40), ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(json.contains("\"urgent\":true"));
+    }
+
+    #[test]
+    fn spike_reset_level_thresholds() {
+        // small rises pass
+        assert_eq!(spike_reset_level(30, 40, 10, None), None);
+        // big rises go back to the previous level
+        assert_eq!(spike_reset_level(30, 100, 10, None), Some(30));
+        // ... or up to the ceiling
+        assert_eq!(spike_reset_level(30, 100, 10, Some(50)), Some(50));
+        assert_eq!(spike_reset_level(30, 45, 10, Some(50)), None);
+        assert_eq!(spike_reset_level(60, 100, 10, Some(50)), Some(60));
+    }
+
+    #[test]
+    fn headphone_detection() {
+        assert!(is_headphone_like(Some("headset"), None));
+        assert!(is_headphone_like(None, Some("analog-output-headphones")));
+        assert!(!is_headphone_like(Some("speaker"), Some("analog-output-speaker")));
+        assert!(!is_headphone_like(None, None));
+    }
+
+    #[test]
+    fn fallback_policy_from_config() {
+        assert_eq!(FallbackPolicy::from_config("none", 30).unwrap(), FallbackPolicy::None);
+        assert_eq!(FallbackPolicy::from_config("mute", 30).unwrap(), FallbackPolicy::Mute);
+        assert_eq!(FallbackPolicy::from_config("cap", 25).unwrap(), FallbackPolicy::Cap(25));
+        assert!(FallbackPolicy::from_config("loud", 30).is_err());
+    }
+
+    #[test]
+    fn speaker_fallback_mutes_or_caps() {
+        let volume = |pct: u16| {
+            let mut cv = ChannelVolumes::default();
+            cv.set_len(2);
+            rules::volume_at_percent(&cv, pct)
+        };
+        let headset = (Sink { sink_name: "bluez_output.headset".into(), headphones: true, active: true, volume_percent: 40, ..Default::default() }, volume(40));
+        let speakers = (Sink { sink_name: "alsa_output.speakers".into(), volume_percent: 80, ..Default::default() }, volume(80));
+        let (mut prev_displayed, mut prev_default) = (None, None);
+
+        let both = [headset.clone(), speakers.clone()];
+        assert!(track_fallback(&both, Some("bluez_output.headset"), &mut prev_displayed, &mut prev_default).is_none());
+        // The headset drops; the speakers take over.
+        let only_speakers = [speakers.clone()];
+        let (sink, cv) = track_fallback(&only_speakers, Some("alsa_output.speakers"), &mut prev_displayed, &mut prev_default).unwrap();
+        assert_eq!(sink.sink_name, "alsa_output.speakers");
+        // Only once.
+        assert!(track_fallback(&only_speakers, Some("alsa_output.speakers"), &mut prev_displayed, &mut prev_default).is_none());
+
+        assert!(matches!(fallback_action(FallbackPolicy::Mute, sink, cv), Some(FallbackAction::Mute)));
+        assert!(fallback_action(FallbackPolicy::Mute, &Sink { mute: true, ..sink.clone() }, cv).is_none());
+        match fallback_action(FallbackPolicy::Cap(30), sink, cv) {
+            Some(FallbackAction::SetVolume(capped)) => assert_eq!(capped.get(), volume(30).get()),
+            other => panic!("expected a capped volume, got {:?}", other),
+        }
+        assert!(fallback_action(FallbackPolicy::Cap(90), sink, cv).is_none());
+        assert!(fallback_action(FallbackPolicy::None, sink, cv).is_none());
+    }
+
+    #[test]
+    fn render_codec_and_profile_format() {
+        let s = Sink {
+            volume_percent: 45,
+            codec: Some("msbc".into()),
+            profile: Some("headset-head-unit".into()),
+            ..Default::default()
+        };
+        let opts = RenderOptions {
+            format: "{icon} {volume}% {battery} {codec} ({profile})",
+            low_quality_color: Some("#FFA500"),
+            ..Default::default()
+        };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        assert!(json.contains("45% mSBC (headset-head-unit)"));
+        assert!(json.contains("\"color\":\"#FFA500\""));
+    }
+
+    #[test]
+    fn expand_format_collapses_empty_values() {
+        let values = [("a", "x".to_string()), ("b", String::new())];
+        assert_eq!(expand_format(" {a}  {b} {c} ", &values), "x {c}");
+    }
+
+    #[test]
+    fn codec_display_names() {
+        assert_eq!(codec_display_name("ldac"), "LDAC");
+        assert_eq!(codec_display_name("aptx_hd"), "aptX HD");
+    }
+
+    #[test]
+    fn battery_target_for_bluetooth_and_usb_sinks() {
+        let bt = Sink { sink_name: "bluez_output.AA_BB_CC_DD_EE_FF.1".into(), ..Default::default() };
+        assert_eq!(battery_target(&bt).unwrap().key, "AA:BB:CC:DD:EE:FF");
+        let usb = Sink { sink_name: "alsa_output.usb-Logitech_G533-00.analog-stereo".into(), bus: Some("usb".into()), ..Default::default() };
+        let t = battery_target(&usb).unwrap();
+        assert_eq!(t.key, usb.sink_name);
+        assert!(t.mac.is_none());
+        let pci = Sink { sink_name: "alsa_output.pci-0000_00_1f.3.analog-stereo".into(), bus: Some("pci".into()), ..Default::default() };
+        assert!(battery_target(&pci).is_none());
+    }
+
+    #[test]
+    fn render_earbuds_with_compact_short_text() {
+        let s = Sink { volume_percent: 40, ..Default::default() };
+        let buds: Battery = [
+            Level { part: battery::Part::Left, percentage: 80, charging: false },
+            Level { part: battery::Part::Right, percentage: 75, charging: false },
+            Level { part: battery::Part::Case, percentage: 40, charging: true },
+        ].into_iter().collect();
+        let opts = RenderOptions { battery: Some(buds), ..Default::default() };
+        let (json, _) = render_sink_output(&s, &opts).unwrap();
+        let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(v["full_text"], format!("{} 40% 🔋L80 R75 C40⚡", CHAR_AUDIO_MEDIUM));
+        assert_eq!(v["short_text"], format!("{} 40% 🔋75%", CHAR_AUDIO_MEDIUM));
+    }
 }
@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// A log file bigger than this is moved to `<file>.1` at startup.
+const MAX_SIZE: u64 = 1024 * 1024;
+
+/// Warnings and errors go to stderr as before; records up to `LOG_LEVEL` also
+/// go to the log file, since i3blocks throws stderr away.
+struct Logger {
+    file_level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            && (metadata.level() <= Level::Warn || metadata.level() <= self.file_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= Level::Warn {
+            eprintln!("{}", record.args());
+        }
+        if let Some(file) = self.file.as_ref().filter(|_| record.level() <= self.file_level) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let thread = std::thread::current();
+            let line = format!("{} {:<5} [{} {}] {}\n",
+                format_timestamp(now.as_millis() as u64),
+                record.level(),
+                std::process::id(),
+                thread.name().unwrap_or("-"),
+                record.args());
+            let _ = file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// `LOG_LEVEL`: `off` (or empty), `error`, `warn`, `info`, `debug` or `trace`.
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    match s.trim() {
+        "" => Ok(LevelFilter::Off),
+        s => s.parse().map_err(|_| format!(
+            "Invalid LOG_LEVEL `{}` (expected off, error, warn, info, debug or trace)", s)),
+    }
+}
+
+/// Install the logger. With a level other than `off`, records up to it are
+/// appended to `path`. A log file that can't be opened is reported on stderr
+/// and skipped: the block keeps running without it.
+pub fn init(level: LevelFilter, path: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let file = match path.filter(|_| level != LevelFilter::Off) {
+        Some(path) => match open_log(&path) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("Failed to open log file {}, logging to stderr only: {}", path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+    log::set_boxed_logger(Box::new(Logger { file_level: level, file }))?;
+    log::set_max_level(level.max(LevelFilter::Warn));
+    Ok(())
+}
+
+/// Open `path` for appending, creating its directory and moving it to
+/// `<path>.1` first if it's grown past [`MAX_SIZE`].
+fn open_log(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if fs::metadata(path).is_ok_and(|m| m.len() > MAX_SIZE) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.mmmZ` for milliseconds since the Unix epoch.
+fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, millis % 1000)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        assert_eq!(parse_level("").unwrap(), LevelFilter::Off);
+        assert_eq!(parse_level("off").unwrap(), LevelFilter::Off);
+        assert_eq!(parse_level("Debug").unwrap(), LevelFilter::Debug);
+        assert_eq!(parse_level(" warn ").unwrap(), LevelFilter::Warn);
+        assert!(parse_level("verbose").is_err());
+    }
+
+    #[test]
+    fn unopenable_log_file_is_an_error() {
+        let file = std::env::temp_dir().join(format!("i3blocks-volume-pw-test-{}-log", std::process::id()));
+        fs::write(&file, "").unwrap();
+        assert!(open_log(&file.join("log")).is_err());
+        let _ = fs::remove_file(file);
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(951_782_400_042), "2000-02-29T00:00:00.042Z");
+        assert_eq!(format_timestamp(1_792_411_199_999), "2026-10-19T11:59:59.999Z");
+    }
+}
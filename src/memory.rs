@@ -0,0 +1,184 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::mpsc, thread, time::Duration};
+
+use libpulse_binding::volume::{ChannelVolumes, Volume};
+use log::error;
+use serde::{Serialize, Deserialize};
+
+/// [`VolumeMemory::save_later`] waits this long for further changes, so a
+/// fade or a burst of scrolling is written once.
+const SAVE_DELAY: Duration = Duration::from_secs(2);
+
+/// Last known volume and mute state of one sink.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SavedVolume {
+    /// Raw per-channel volumes.
+    pub volume: Vec<u32>,
+    pub mute: bool,
+}
+
+impl SavedVolume {
+    pub fn new(cv: &ChannelVolumes, mute: bool) -> Self {
+        Self { volume: cv.get().iter().map(|v| v.0).collect(), mute }
+    }
+
+    /// The saved volume laid out on `current`'s channels. If the channel count
+    /// changed (e.g. a different Bluetooth profile), every channel gets the
+    /// saved average.
+    pub fn apply_to(&self, current: &ChannelVolumes) -> ChannelVolumes {
+        let mut out = *current;
+        if self.volume.len() == out.len() as usize {
+            for (v, saved) in out.get_mut().iter_mut().zip(&self.volume) {
+                *v = Volume(*saved);
+            }
+        } else if !self.volume.is_empty() {
+            let avg = self.volume.iter().map(|&v| v as u64).sum::<u64>() / self.volume.len() as u64;
+            for v in out.get_mut() {
+                *v = Volume(avg as u32);
+            }
+        }
+        out
+    }
+}
+
+type Entries = HashMap<String, SavedVolume>;
+
+/// Per-sink volume memory, persisted as JSON so it survives restarts.
+pub struct VolumeMemory {
+    path: Option<PathBuf>,
+    entries: Entries,
+    /// Sink names that are never remembered or restored. A trailing `*` matches by prefix.
+    exclude: Vec<String>,
+    /// Background writer started by the first [`VolumeMemory::save_later`].
+    saver: Option<(mpsc::Sender<Entries>, thread::JoinHandle<()>)>,
+}
+
+impl VolumeMemory {
+    /// Load the memory from `path` (best-effort: a missing or corrupt file starts empty).
+    pub fn load(path: Option<PathBuf>, exclude: Vec<String>) -> Self {
+        let entries = path.as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        Self { path, entries, exclude, saver: None }
+    }
+
+    pub fn get(&self, sink_name: &str) -> Option<&SavedVolume> {
+        self.entries.get(sink_name)
+    }
+
+    /// Remember `saved` for `sink_name`. Returns `true` if the entry changed.
+    pub fn record(&mut self, sink_name: &str, saved: SavedVolume) -> bool {
+        if self.entries.get(sink_name) == Some(&saved) {
+            return false;
+        }
+        self.entries.insert(sink_name.to_string(), saved);
+        true
+    }
+
+    pub fn is_excluded(&self, sink_name: &str) -> bool {
+        self.exclude.iter().any(|e| match e.strip_suffix('*') {
+            Some(prefix) => sink_name.starts_with(prefix),
+            None => sink_name == e,
+        })
+    }
+
+    /// Write the memory back to its file from a background thread once there have been no changes for
+    /// [`SAVE_DELAY`], keeping file writes off the event loop.
+    pub fn save_later(&mut self) {
+        let Some(path) = self.path.clone() else { return };
+        if self.saver.is_none() {
+            let (tx, rx) = mpsc::channel::<Entries>();
+            let spawned = thread::Builder::new().name("volume-memory".to_string()).spawn(move || {
+                while let Ok(mut entries) = rx.recv() {
+                    // Disconnected (dropped) also ends the wait: write what's pending.
+                    while let Ok(newer) = rx.recv_timeout(SAVE_DELAY) {
+                        entries = newer;
+                    }
+                    if let Err(e) = write_entries(&path, &entries) {
+                        error!("Error saving volume memory: {}", e);
+                    }
+                }
+            });
+            match spawned {
+                Ok(handle) => self.saver = Some((tx, handle)),
+                Err(e) => error!("Error spawning volume-memory thread: {}", e),
+            }
+        }
+        if let Some((tx, _)) = &self.saver {
+            let _ = tx.send(self.entries.clone());
+        }
+    }
+}
+
+/// Pending changes are written before the memory goes away.
+impl Drop for VolumeMemory {
+    fn drop(&mut self) {
+        if let Some((tx, handle)) = self.saver.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Write `entries` to `path` atomically, via a temporary file.
+fn write_entries(path: &Path, entries: &Entries) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(entries)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn channels(vols: &[u32]) -> ChannelVolumes {
+        let mut cv = ChannelVolumes::default();
+        cv.set_len(vols.len() as u8);
+        for (v, raw) in cv.get_mut().iter_mut().zip(vols) {
+            *v = Volume(*raw);
+        }
+        cv
+    }
+
+    #[test]
+    fn record_reports_changes() {
+        let mut m = VolumeMemory::load(None, Vec::new());
+        let saved = SavedVolume::new(&channels(&[100, 200]), false);
+        assert!(m.record("a", saved.clone()));
+        assert!(!m.record("a", saved));
+        assert!(m.record("a", SavedVolume::new(&channels(&[100, 200]), true)));
+    }
+
+    #[test]
+    fn exclude_matches_exact_and_prefix() {
+        let m = VolumeMemory::load(None, vec!["alsa_output.hdmi".into(), "bluez_output.*".into()]);
+        assert!(m.is_excluded("alsa_output.hdmi"));
+        assert!(!m.is_excluded("alsa_output.hdmi-2"));
+        assert!(m.is_excluded("bluez_output.AA_BB_CC_DD_EE_FF.1"));
+    }
+
+    #[test]
+    fn apply_to_mismatched_channels_uses_average() {
+        let saved = SavedVolume::new(&channels(&[100, 300]), false);
+        assert_eq!(saved.apply_to(&channels(&[0, 0])).get(), &[Volume(100), Volume(300)]);
+        assert_eq!(saved.apply_to(&channels(&[0])).get(), &[Volume(200)]);
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("i3blocks-volume-pw-test-{}-volume-memory/volumes.json", std::process::id()));
+        let mut m = VolumeMemory::load(Some(path.clone()), Vec::new());
+        // Saved in the background, at the latest when the memory is dropped.
+        m.record("sink", SavedVolume::new(&channels(&[42]), true));
+        m.save_later();
+        m.record("sink", SavedVolume::new(&channels(&[8]), false));
+        m.save_later();
+        let expected = m.get("sink").cloned();
+        drop(m);
+        assert_eq!(VolumeMemory::load(Some(path.clone()), Vec::new()).get("sink"), expected.as_ref());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use zbus::blocking::{fdo::DBusProxy, Connection, MessageIterator, Proxy};
+use zbus::names::BusName;
+use zbus::{MatchRule, MessageType};
+use zbus::zvariant::{Array, OwnedValue};
+
+use super::session_bus;
+
+/// Bus names of MPRIS players start with this.
+const PLAYER_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+/// Characters in front of a playing and a paused track.
+const CHAR_PLAYING: char = '\u{25B6}';
+const CHAR_PAUSED: char = '\u{23F8}';
+
+/// An MPRIS media player on the session bus.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Player {
+    /// Example: `org.mpris.MediaPlayer2.spotify`
+    pub bus_name: String,
+    /// Process owning the bus name, to match it with a sink input.
+    pub pid: Option<u32>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub playing: bool,
+}
+
+impl Player {
+    /// `▶ Artist – Title`, or `⏸ Title` for a paused player without an artist.
+    /// `None` without a title.
+    pub fn label(&self) -> Option<String> {
+        let title = self.title.as_deref().filter(|t| !t.is_empty())?;
+        let glyph = if self.playing { CHAR_PLAYING } else { CHAR_PAUSED };
+        Some(match &self.artist {
+            Some(artist) => format!("{} {} – {}", glyph, artist, title),
+            None => format!("{} {}", glyph, title),
+        })
+    }
+}
+
+/// The player to show and control: preferably one that's playing from a
+/// process with a sink input on the displayed sink (`sink_pids`), then any
+/// that's playing, then any on the sink, then the first.
+pub fn choose_player<'a>(players: &'a [Player], sink_pids: &[u32]) -> Option<&'a Player> {
+    let on_sink = |p: &Player| p.pid.is_some_and(|pid| sink_pids.contains(&pid));
+    players.iter().find(|p| p.playing && on_sink(p))
+        .or_else(|| players.iter().find(|p| p.playing))
+        .or_else(|| players.iter().find(|p| on_sink(p)))
+        .or_else(|| players.first())
+}
+
+/// Call a method without arguments (`PlayPause`, `Next`, `Previous`) on a player.
+pub fn send(bus_name: &str, method: &str) -> zbus::Result<()> {
+    let conn = session_bus().ok_or_else(|| zbus::Error::Failure("no session bus".into()))?;
+    let proxy = Proxy::new(&conn, bus_name, PLAYER_PATH, PLAYER_INTERFACE)?;
+    let () = proxy.call(method, &())?;
+    Ok(())
+}
+
+/// Call `on_change` with all players now and whenever a player appears,
+/// disappears or changes its track or playback status. Blocks for as long as
+/// the session-bus connection lives; returns on error.
+pub fn watch_players(mut on_change: impl FnMut(Vec<Player>)) -> zbus::Result<()> {
+    let conn = session_bus().ok_or_else(|| zbus::Error::Failure("no session bus".into()))?;
+    // Start listening before adding the match rules so no signal slips through.
+    let messages = MessageIterator::from(&conn);
+    let dbus = DBusProxy::new(&conn)?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path(PLAYER_PATH)?
+        .arg(0, PLAYER_INTERFACE)?
+        .build())?;
+    dbus.add_match_rule(MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build())?;
+    on_change(list_players(&conn, &dbus)?);
+
+    for msg in messages {
+        let msg = msg?;
+        let relevant = match (msg.interface(), msg.member()) {
+            (Some(i), Some(m)) if i == "org.freedesktop.DBus.Properties" && m == "PropertiesChanged" => {
+                msg.path().is_some_and(|p| p.as_str() == PLAYER_PATH)
+            }
+            (Some(i), Some(m)) if i == "org.freedesktop.DBus" && m == "NameOwnerChanged" => {
+                msg.body::<(String, String, String)>().is_ok_and(|(name, _, _)| name.starts_with(PLAYER_PREFIX))
+            }
+            _ => false,
+        };
+        if relevant {
+            on_change(list_players(&conn, &dbus)?);
+        }
+    }
+    Ok(())
+}
+
+/// Every player on the bus, skipping ones that don't answer.
+fn list_players(conn: &Connection, dbus: &DBusProxy) -> zbus::Result<Vec<Player>> {
+    let mut players: Vec<Player> = dbus.list_names()?.into_iter()
+        .filter(|name| name.starts_with(PLAYER_PREFIX))
+        .filter_map(|name| read_player(conn, dbus, name.as_str()).ok())
+        .collect();
+    players.sort_by(|a, b| a.bus_name.cmp(&b.bus_name));
+    Ok(players)
+}
+
+fn read_player(conn: &Connection, dbus: &DBusProxy, bus_name: &str) -> zbus::Result<Player> {
+    let proxy = Proxy::new(conn, bus_name, PLAYER_PATH, PLAYER_INTERFACE)?;
+    let status: String = proxy.get_property("PlaybackStatus")?;
+    let metadata: HashMap<String, OwnedValue> = proxy.get_property("Metadata").unwrap_or_default();
+    let (artist, title) = parse_metadata(&metadata);
+    let pid = BusName::try_from(bus_name).ok()
+        .and_then(|name| dbus.get_connection_unix_process_id(name).ok());
+    Ok(Player { bus_name: bus_name.to_string(), pid, artist, title, playing: status == "Playing" })
+}
+
+/// Artist(s) and title from MPRIS `Metadata`. `xesam:artist` is a list, but
+/// some players send a single string.
+fn parse_metadata(metadata: &HashMap<String, OwnedValue>) -> (Option<String>, Option<String>) {
+    let artist = metadata.get("xesam:artist").and_then(|v| match v.downcast_ref::<Array>() {
+        Some(list) => Some(list.get().iter().filter_map(|a| a.downcast_ref::<str>()).collect::<Vec<_>>().join(", ")),
+        None => v.downcast_ref::<str>().map(str::to_string),
+    }).filter(|a| !a.is_empty());
+    let title = metadata.get("xesam:title")
+        .and_then(|v| v.downcast_ref::<str>())
+        .filter(|t| !t.is_empty())
+        .map(str::to_string);
+    (artist, title)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use zbus::zvariant::{Str, Value};
+
+    fn player(name: &str, pid: u32, playing: bool) -> Player {
+        Player { bus_name: name.into(), pid: Some(pid), title: Some("Song".into()), playing, ..Default::default() }
+    }
+
+    #[test]
+    fn choose_prefers_playing_on_sink() {
+        let players = [player("a", 1, false), player("b", 2, true), player("c", 3, true)];
+        assert_eq!(choose_player(&players, &[3]).unwrap().bus_name, "c");
+        assert_eq!(choose_player(&players, &[9]).unwrap().bus_name, "b");
+        assert_eq!(choose_player(&players[..1], &[]).unwrap().bus_name, "a");
+        assert_eq!(choose_player(&[], &[1]), None);
+    }
+
+    #[test]
+    fn metadata_and_label() {
+        let metadata: HashMap<String, OwnedValue> = [
+            ("xesam:artist".to_string(), OwnedValue::from(Value::from(vec!["Daft Punk", "Pharrell"]))),
+            ("xesam:title".to_string(), OwnedValue::from(Str::from("Get Lucky"))),
+        ].into_iter().collect();
+        let (artist, title) = parse_metadata(&metadata);
+        let p = Player { artist, title, playing: true, ..Default::default() };
+        assert_eq!(p.label().unwrap(), "▶ Daft Punk, Pharrell – Get Lucky");
+        let single: HashMap<String, OwnedValue> = [("xesam:artist".to_string(), OwnedValue::from(Str::from("Solo")))].into_iter().collect();
+        assert_eq!(parse_metadata(&single), (Some("Solo".into()), None));
+        assert_eq!(Player { title: Some("Podcast".into()), ..Default::default() }.label().unwrap(), "⏸ Podcast");
+        assert_eq!(Player::default().label(), None);
+    }
+}
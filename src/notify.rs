@@ -0,0 +1,180 @@
+use std::{collections::HashMap, error::Error, sync::mpsc, thread};
+
+use log::debug;
+
+use zbus::blocking::Proxy;
+use zbus::zvariant::Value;
+
+use super::session_bus;
+
+/// A battery has to rise this many percent above a notified threshold (e.g.
+/// after charging) before crossing it again notifies again, so a level that
+/// flickers around the threshold notifies only once.
+const RENOTIFY_HYSTERESIS: u8 = 5;
+
+/// `urgency` hint values of the notification spec.
+const URGENCY_LOW: u8 = 0;
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+
+/// Parse `LOW_BATTERY_NOTIFY`, e.g. `20,10`, into thresholds sorted high to low.
+pub fn parse_thresholds(list: &[String]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut thresholds = Vec::with_capacity(list.len());
+    for item in list {
+        match item.trim_end_matches('%').parse::<u8>() {
+            Ok(t) if (1..=100).contains(&t) => thresholds.push(t),
+            _ => return Err(format!("Invalid LOW_BATTERY_NOTIFY threshold `{}`", item).into()),
+        }
+    }
+    thresholds.sort_unstable_by(|a, b| b.cmp(a));
+    thresholds.dedup();
+    Ok(thresholds)
+}
+
+/// Decide whether a battery at `percentage` should notify, given the lowest
+/// threshold already notified for the device (`notified`). A threshold counts
+/// as crossed once the battery is at or below it. Returns the threshold to
+/// notify about (the lowest one crossed, so a big drop sends one notification)
+/// and the device's new `notified` value.
+pub fn low_battery_threshold(thresholds: &[u8], notified: Option<u8>, percentage: u8) -> (Option<u8>, Option<u8>) {
+    let notified = notified.filter(|&n| percentage <= n.saturating_add(RENOTIFY_HYSTERESIS));
+    let crossed = thresholds.iter().copied()
+        .filter(|&t| percentage <= t)
+        .min();
+    match crossed {
+        Some(t) if notified.is_none_or(|n| t < n) => (Some(t), Some(t)),
+        _ => (None, notified),
+    }
+}
+
+/// Show a low-battery notification for `device` (best-effort). `lowest` marks
+/// the last configured threshold, which is sent as critical.
+pub fn notify_low_battery(device: &str, percentage: u8, lowest: bool) -> zbus::Result<()> {
+    let conn = session_bus().ok_or_else(|| zbus::Error::Failure("no session bus".into()))?;
+    let proxy = Proxy::new(&conn, "org.freedesktop.Notifications", "/org/freedesktop/Notifications", "org.freedesktop.Notifications")?;
+    let mut hints: HashMap<&str, Value> = HashMap::new();
+    hints.insert("urgency", Value::U8(if lowest { URGENCY_CRITICAL } else { URGENCY_NORMAL }));
+    let summary = format!("{} battery low", if device.is_empty() { "Audio device" } else { device });
+    let body = format!("{}% remaining", percentage);
+    let _: u32 = proxy.call("Notify", &(
+        "i3blocks-volume-pw",
+        0u32,
+        if lowest { "battery-caution" } else { "battery-low" },
+        summary.as_str(),
+        body.as_str(),
+        Vec::<&str>::new(),
+        hints,
+        -1i32,
+    ))?;
+    Ok(())
+}
+
+/// What the volume OSD shows for the displayed sink.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VolumeOsd {
+    pub sink: String,
+    pub device: String,
+    pub volume: u16,
+    pub muted: bool,
+}
+
+impl VolumeOsd {
+    /// Freedesktop icon name for the volume level.
+    fn icon(&self) -> &'static str {
+        match (self.muted, self.volume) {
+            (true, _) | (false, 0) => "audio-volume-muted",
+            (false, 1..=33) => "audio-volume-low",
+            (false, 34..=66) => "audio-volume-medium",
+            (false, _) => "audio-volume-high",
+        }
+    }
+}
+
+/// Start the volume OSD thread. Send it the displayed sink's state after every
+/// render: the first state and repeats are ignored, and every change updates a
+/// single notification (via `replaces_id`) instead of stacking new ones.
+pub fn spawn_volume_osd() -> std::io::Result<mpsc::Sender<VolumeOsd>> {
+    let (tx, rx) = mpsc::channel::<VolumeOsd>();
+    thread::Builder::new().name("volume-osd".to_string()).spawn(move || {
+        let mut last: Option<VolumeOsd> = None;
+        let mut id = 0u32;
+        while let Ok(mut osd) = rx.recv() {
+            // Only the latest of a burst (e.g. a fade) is worth showing.
+            while let Ok(newer) = rx.try_recv() {
+                osd = newer;
+            }
+            if last.replace(osd.clone()).is_none_or(|l| l == osd) {
+                continue;
+            }
+            match notify_volume(&osd, id) {
+                Ok(new_id) => id = new_id,
+                Err(e) => debug!("Failed to show the volume notification: {}", e),
+            }
+        }
+    })?;
+    Ok(tx)
+}
+
+/// Show or replace the volume notification, with the level as a `value` hint
+/// for a progress bar. Returns the notification's id.
+fn notify_volume(osd: &VolumeOsd, replaces_id: u32) -> zbus::Result<u32> {
+    let conn = session_bus().ok_or_else(|| zbus::Error::Failure("no session bus".into()))?;
+    let proxy = Proxy::new(&conn, "org.freedesktop.Notifications", "/org/freedesktop/Notifications", "org.freedesktop.Notifications")?;
+    let mut hints: HashMap<&str, Value> = HashMap::new();
+    hints.insert("urgency", Value::U8(URGENCY_LOW));
+    hints.insert("value", Value::I32(osd.volume.min(100) as i32));
+    // Lets daemons such as dunst and notify-osd replace it even without the id.
+    hints.insert("x-canonical-private-synchronous", Value::from("i3blocks-volume-pw"));
+    let summary = if osd.device.is_empty() { "Volume" } else { osd.device.as_str() };
+    let body = if osd.muted { "Muted".to_string() } else { format!("{}%", osd.volume) };
+    proxy.call("Notify", &(
+        "i3blocks-volume-pw",
+        replaces_id,
+        osd.icon(),
+        summary,
+        body.as_str(),
+        Vec::<&str>::new(),
+        hints,
+        -1i32,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_thresholds_sorts_and_validates() {
+        assert_eq!(parse_thresholds(&["10".into(), "20%".into(), "10".into()]).unwrap(), vec![20, 10]);
+        assert!(parse_thresholds(&[]).unwrap().is_empty());
+        assert!(parse_thresholds(&["0".into()]).is_err());
+        assert!(parse_thresholds(&["low".into()]).is_err());
+    }
+
+    #[test]
+    fn notifies_once_per_threshold() {
+        let t = [20, 10];
+        assert_eq!(low_battery_threshold(&t, None, 50), (None, None));
+        assert_eq!(low_battery_threshold(&t, None, 19), (Some(20), Some(20)));
+        assert_eq!(low_battery_threshold(&t, None, 20), (Some(20), Some(20)));
+        assert_eq!(low_battery_threshold(&t, None, 21), (None, None));
+        assert_eq!(low_battery_threshold(&t, Some(20), 18), (None, Some(20)));
+        // Flickering just above the threshold doesn't re-arm it.
+        assert_eq!(low_battery_threshold(&t, Some(20), 21), (None, Some(20)));
+        assert_eq!(low_battery_threshold(&t, Some(20), 9), (Some(10), Some(10)));
+        // A big drop sends one notification for the lowest threshold.
+        assert_eq!(low_battery_threshold(&t, None, 5), (Some(10), Some(10)));
+        // Charging well above the threshold re-arms it.
+        assert_eq!(low_battery_threshold(&t, Some(10), 40), (None, None));
+    }
+
+    #[test]
+    fn volume_osd_icons() {
+        let osd = |volume, muted| VolumeOsd { sink: String::new(), device: String::new(), volume, muted };
+        assert_eq!(osd(50, true).icon(), "audio-volume-muted");
+        assert_eq!(osd(0, false).icon(), "audio-volume-muted");
+        assert_eq!(osd(20, false).icon(), "audio-volume-low");
+        assert_eq!(osd(50, false).icon(), "audio-volume-medium");
+        assert_eq!(osd(120, false).icon(), "audio-volume-high");
+    }
+}
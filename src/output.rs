@@ -0,0 +1,355 @@
+use std::error::Error;
+
+use super::protocol::{Header, Output, WaybarOutput};
+use super::{CONT_SIGNAL, STOP_SIGNAL};
+
+/// Background of an urgent block on bars without an urgent flag.
+const URGENT_BACKGROUND: &str = "#900000";
+
+/// The battery part of a block's text, for backends that can color it.
+pub struct BatterySegment {
+    /// As it appears in `full_text` and in `short_text`.
+    pub full: String,
+    pub short: String,
+    pub color: String,
+}
+
+/// One rendered block plus the sink state behind it. Backends turn this into
+/// whatever their bar reads.
+#[derive(Default)]
+pub struct Block {
+    pub full_text: String,
+    /// Multi-line detailed view, for bars with tooltips.
+    pub tooltip: Option<String>,
+    /// Shorter text for when the bar runs out of room, if it differs.
+    pub short_text: Option<String>,
+    /// Text color (e.g. while on a low-quality codec).
+    pub color: Option<String>,
+    pub battery: Option<BatterySegment>,
+    pub urgent: bool,
+    pub volume: u16,
+    pub muted: bool,
+    pub bluetooth: bool,
+    pub device_name: String,
+    pub sink_name: String,
+}
+
+/// Serializes rendered blocks for one kind of bar.
+pub trait Backend {
+    /// Lines printed once at startup, e.g. the i3bar header.
+    fn preamble(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// The line for `block`; `first` is the first block after the preamble.
+    fn render(&self, block: &Block, first: bool) -> Result<String, Box<dyn Error>>;
+
+    /// Whether the preamble announces [`STOP_SIGNAL`] and [`CONT_SIGNAL`] in an
+    /// i3bar header, so the bar may send them.
+    fn announces_stop_signals(&self) -> bool {
+        false
+    }
+
+    /// Whether [`Block::tooltip`] is shown.
+    fn shows_tooltip(&self) -> bool {
+        false
+    }
+
+    /// Whether the bar sends clicks on stdin (and closes it when it goes away).
+    /// Without it, commands come through signals or the command socket, and a
+    /// failed write to stdout means the bar is gone.
+    fn reads_stdin(&self) -> bool {
+        true
+    }
+}
+
+/// Pick the backend for `OUTPUT_FORMAT`. `volume_app` is what a left click on
+/// bars with their own action syntax launches.
+pub fn from_config(format: &str, print_header: bool, volume_app: &str) -> Result<Box<dyn Backend>, Box<dyn Error>> {
+    match format {
+        "i3blocks" | "" => Ok(Box::new(I3blocks { print_header })),
+        "i3bar" | "swaybar" => Ok(Box::new(I3bar)),
+        "waybar" => Ok(Box::new(Waybar)),
+        "polybar" => Ok(Box::new(Polybar { actions: Actions::new(volume_app) })),
+        "lemonbar" => Ok(Box::new(Lemonbar { actions: Actions::new(volume_app) })),
+        "plain" => Ok(Box::new(Plain)),
+        other => Err(format!(
+            "Invalid OUTPUT_FORMAT `{}` (expected i3blocks, i3bar, waybar, polybar, lemonbar or plain)", other).into()),
+    }
+}
+
+/// Escape text for Pango markup.
+pub fn pango_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('\'', "&#39;").replace('"', "&quot;")
+}
+
+/// `text` with its first occurrence of `segment` passed through `wrap` and
+/// everything passed through `escape`.
+fn wrap_segment(text: &str, segment: &str, escape: impl Fn(&str) -> String, wrap: impl Fn(String) -> String) -> String {
+    match text.find(segment).filter(|_| !segment.is_empty()) {
+        Some(at) => format!("{}{}{}",
+            escape(&text[..at]),
+            wrap(escape(segment)),
+            escape(&text[at + segment.len()..])),
+        None => escape(text),
+    }
+}
+
+/// The full and short text, with the battery segment in a Pango color span.
+fn pango_texts(block: &Block) -> (String, Option<String>) {
+    match &block.battery {
+        None => (block.full_text.clone(), block.short_text.clone()),
+        Some(b) => {
+            let span = |t: String| format!("<span foreground=\"{}\">{}</span>", pango_escape(&b.color), t);
+            (wrap_segment(&block.full_text, &b.full, pango_escape, span),
+             block.short_text.as_deref().map(|s| wrap_segment(s, &b.short, pango_escape, span)))
+        }
+    }
+}
+
+/// The i3bar/i3blocks block object.
+fn i3bar_output(block: &Block) -> Output {
+    let (full_text, short_text) = pango_texts(block);
+    Output {
+        full_text,
+        short_text,
+        color: block.color.clone(),
+        urgent: block.urgent.then_some(true),
+        markup: block.battery.is_some().then(|| "pango".to_string()),
+        ..Default::default()
+    }
+}
+
+/// One JSON object per line, for i3blocks.
+pub struct I3blocks {
+    /// Print the i3bar protocol header first (`PRINT_HEADER`).
+    pub print_header: bool,
+}
+
+impl Backend for I3blocks {
+    fn preamble(&self) -> Vec<String> {
+        if self.print_header { vec![header()] } else { Vec::new() }
+    }
+
+    fn announces_stop_signals(&self) -> bool {
+        self.print_header
+    }
+
+    fn render(&self, block: &Block, _first: bool) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string(&i3bar_output(block))?)
+    }
+}
+
+fn header() -> String {
+    let header = Header {
+        version: 1,
+        click_events: Some(true),
+        stop_signal: Some(STOP_SIGNAL as u8),
+        cont_signal: Some(CONT_SIGNAL as u8),
+    };
+    serde_json::to_string(&header).unwrap_or_default()
+}
+
+/// The i3bar protocol, for running as an i3bar/swaybar `status_command`:
+/// header, `[`, then `[{..}]`, `,[{..}]`, ...
+pub struct I3bar;
+
+impl Backend for I3bar {
+    fn preamble(&self) -> Vec<String> {
+        vec![header(), "[".to_string()]
+    }
+
+    fn announces_stop_signals(&self) -> bool {
+        true
+    }
+
+    fn render(&self, block: &Block, first: bool) -> Result<String, Box<dyn Error>> {
+        let line = serde_json::to_string(&i3bar_output(block))?;
+        Ok(format!("{}[{}]", if first { "" } else { "," }, line))
+    }
+}
+
+/// Waybar custom-module JSON (`"return-type": "json"`). Waybar reads `text`
+/// as Pango markup, so it's always escaped.
+pub struct Waybar;
+
+impl Backend for Waybar {
+    fn render(&self, block: &Block, _first: bool) -> Result<String, Box<dyn Error>> {
+        let mut class = Vec::new();
+        if block.muted {
+            class.push("muted".to_string());
+        }
+        if block.bluetooth {
+            class.push("bluetooth".to_string());
+        }
+        if block.volume > 100 {
+            class.push("over-amplified".to_string());
+        }
+        let text = match &block.battery {
+            Some(_) => pango_texts(block).0,
+            None => pango_escape(&block.full_text),
+        };
+        let out = WaybarOutput {
+            text,
+            alt: Some(class.first().cloned().unwrap_or_else(|| "default".to_string())),
+            tooltip: Some(pango_escape(block.tooltip.as_deref()
+                .unwrap_or(if block.device_name.is_empty() { &block.sink_name } else { &block.device_name }))),
+            class,
+            percentage: block.volume,
+        };
+        Ok(serde_json::to_string(&out)?)
+    }
+
+    fn shows_tooltip(&self) -> bool {
+        true
+    }
+
+    fn reads_stdin(&self) -> bool {
+        false
+    }
+}
+
+/// Shell commands bound to clicks on bars with `%{A}` action tags. Volume and
+/// mute go to this process through the real-time signals of [`super::signal_command`].
+struct Actions {
+    left: String,
+    middle: String,
+    scroll_up: String,
+    scroll_down: String,
+}
+
+impl Actions {
+    fn new(volume_app: &str) -> Self {
+        let pid = std::process::id();
+        let rt = |n: i32| format!("kill -RTMIN+{} {}", n, pid);
+        Self { left: volume_app.to_string(), middle: rt(3), scroll_up: rt(1), scroll_down: rt(2) }
+    }
+
+    /// `text` wrapped in click, scroll and color tags (polybar/lemonbar syntax).
+    fn tag(&self, block: &Block) -> String {
+        let text = wrap_segment(
+            &block.full_text,
+            block.battery.as_ref().map_or("", |b| b.full.as_str()),
+            tag_escape,
+            |t| format!("%{{F{}}}{}%{{F-}}", block.battery.as_ref().map_or("", |b| b.color.as_str()), t));
+        let mut out = String::new();
+        for (button, command) in [(1, &self.left), (2, &self.middle), (4, &self.scroll_up), (5, &self.scroll_down)] {
+            out.push_str(&format!("%{{A{}:{}:}}", button, command.replace(':', "\\:")));
+        }
+        if block.urgent {
+            out.push_str(&format!("%{{B{}}}", URGENT_BACKGROUND));
+        }
+        match &block.color {
+            Some(c) => out.push_str(&format!("%{{F{}}}{}%{{F-}}", c, text)),
+            None => out.push_str(&text),
+        }
+        if block.urgent {
+            out.push_str("%{B-}");
+        }
+        out.push_str(&"%{A}".repeat(4));
+        out
+    }
+}
+
+/// Keep text from being read as a `%{..}` tag. Every piece of text is followed
+/// by a tag, and `%` right before one would read as an escaped `%%{`, so text
+/// ending in `%` (like `50%`) gets a zero-width space at the joint.
+fn tag_escape(s: &str) -> String {
+    let mut out = s.replace("%{", "%%{");
+    if out.ends_with('%') {
+        out.push('\u{200B}');
+    }
+    out
+}
+
+/// Polybar `script` module with `tail = true`: one tagged line per update.
+pub struct Polybar {
+    actions: Actions,
+}
+
+impl Backend for Polybar {
+    fn render(&self, block: &Block, _first: bool) -> Result<String, Box<dyn Error>> {
+        Ok(self.actions.tag(block))
+    }
+
+    fn reads_stdin(&self) -> bool {
+        false
+    }
+}
+
+/// Lemonbar input: one right-aligned, tagged line per update. Lemonbar prints
+/// clicked actions on its stdout, so pipe it into `sh`.
+pub struct Lemonbar {
+    actions: Actions,
+}
+
+impl Backend for Lemonbar {
+    fn render(&self, block: &Block, _first: bool) -> Result<String, Box<dyn Error>> {
+        Ok(format!("%{{r}}{}", self.actions.tag(block)))
+    }
+
+    fn reads_stdin(&self) -> bool {
+        false
+    }
+}
+
+/// The full text, one line per update, for scripts and simple bars.
+pub struct Plain;
+
+impl Backend for Plain {
+    fn render(&self, block: &Block, _first: bool) -> Result<String, Box<dyn Error>> {
+        Ok(block.full_text.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block() -> Block {
+        Block {
+            full_text: "🔉 50% 🪫20% [Tom & Jerry]".into(),
+            battery: Some(BatterySegment { full: "🪫20%".into(), short: "🪫20%".into(), color: "#FF0000".into() }),
+            volume: 50,
+            device_name: "Tom & Jerry".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn i3bar_frames_and_colors_battery() {
+        let b = block();
+        assert_eq!(I3bar.preamble()[1], "[");
+        let first = I3bar.render(&b, true).unwrap();
+        assert!(first.starts_with("[{") && first.ends_with("}]"));
+        assert!(I3bar.render(&b, false).unwrap().starts_with(",[{"));
+        let v: serde_json::Value = serde_json::from_str(&I3blocks { print_header: false }.render(&b, true).unwrap()).unwrap();
+        assert_eq!(v["full_text"], "🔉 50% <span foreground=\"#FF0000\">🪫20%</span> [Tom &amp; Jerry]");
+        assert_eq!(v["markup"], "pango");
+    }
+
+    #[test]
+    fn polybar_tags_actions_and_color() {
+        let p = Polybar { actions: Actions::new("pavu:control") };
+        let line = p.render(&Block { urgent: true, ..block() }, true).unwrap();
+        let pid = std::process::id();
+        assert!(line.starts_with(&format!("%{{A1:pavu\\:control:}}%{{A2:kill -RTMIN+3 {}:}}", pid)));
+        assert!(line.contains("%{B#900000}🔉 50% %{F#FF0000}🪫20%\u{200B}%{F-} [Tom & Jerry]%{B-}"));
+        assert!(!line.contains("%%{"));
+        let plain = p.render(&Block { full_text: "🔉 50%".into(), ..Default::default() }, true).unwrap();
+        assert!(plain.ends_with("🔉 50%\u{200B}%{A}%{A}%{A}%{A}"));
+        assert_eq!(tag_escape("100%{x}"), "100%%{x}");
+        assert!(line.ends_with("%{A}%{A}%{A}%{A}"));
+        assert!(Lemonbar { actions: Actions::new("x") }.render(&block(), true).unwrap().starts_with("%{r}%{A1:x:}"));
+    }
+
+    #[test]
+    fn plain_and_config() {
+        assert_eq!(Plain.render(&block(), true).unwrap(), "🔉 50% 🪫20% [Tom & Jerry]");
+        assert!(!from_config("waybar", false, "x").unwrap().reads_stdin());
+        assert!(from_config("i3blocks", true, "x").unwrap().preamble().len() == 1);
+        assert!(from_config("i3blocks", true, "x").unwrap().announces_stop_signals());
+        assert!(!from_config("i3blocks", false, "x").unwrap().announces_stop_signals());
+        assert!(from_config("dzen", false, "x").is_err());
+    }
+}
@@ -84,3 +84,21 @@ pub struct Output {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub markup: Option<String>,
 }
+
+/// Waybar custom-module output JSON object (`"return-type": "json"`).
+#[derive(Serialize, Default)]
+pub struct WaybarOutput {
+    /// Ex: `🔊 45%`
+    pub text: String,
+    /// Ex: `muted`; selects the entry of a `format-icons` object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt: Option<String>,
+    /// Ex: `WH-1000XM4`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tooltip: Option<String>,
+    /// Ex: `["muted", "bluetooth"]`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub class: Vec<String>,
+    /// Ex: `45`; indexes a `format-icons` array.
+    pub percentage: u16,
+}
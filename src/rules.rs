@@ -0,0 +1,133 @@
+use std::error::Error;
+
+use libpulse_binding::volume::{ChannelVolumes, Volume};
+use regex::Regex;
+use serde::Deserialize;
+
+/// One entry of the `SINK_RULES` JSON array, as written by the user.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    name: Option<String>,
+    regex: Option<String>,
+    step: Option<u8>,
+    max: Option<u16>,
+    default: Option<u16>,
+}
+
+enum Matcher {
+    Name(String),
+    Regex(Regex),
+}
+
+/// Per-sink overrides for the volume step, the volume ceiling and the volume a
+/// sink is set to when it appears.
+pub struct SinkRule {
+    matcher: Matcher,
+    /// Volume step in percent, replacing `AUDIO_DELTA`.
+    pub step: Option<u8>,
+    /// Highest volume in percent the block will set.
+    pub max: Option<u16>,
+    /// Volume in percent applied when the sink appears (unless one is remembered).
+    pub default: Option<u16>,
+}
+
+impl SinkRule {
+    fn matches(&self, sink_name: &str) -> bool {
+        match &self.matcher {
+            Matcher::Name(n) => n == sink_name,
+            Matcher::Regex(re) => re.is_match(sink_name),
+        }
+    }
+}
+
+/// Ordered list of sink rules; the first matching rule applies.
+#[derive(Default)]
+pub struct SinkRules(Vec<SinkRule>);
+
+impl SinkRules {
+    /// Parse a JSON array such as
+    /// `[{"regex": "^bluez_output\\.", "step": 2, "max": 60}, {"name": "alsa_output.usb-dac", "default": 40}]`.
+    /// An empty string means no rules.
+    pub fn parse(json: &str) -> Result<Self, Box<dyn Error>> {
+        if json.trim().is_empty() {
+            return Ok(Self::default());
+        }
+        let raw: Vec<RawRule> = serde_json::from_str(json)
+            .map_err(|e| format!("Invalid SINK_RULES: {}", e))?;
+        let mut rules = Vec::with_capacity(raw.len());
+        for r in raw {
+            let matcher = match (r.name, r.regex) {
+                (Some(n), None) => Matcher::Name(n),
+                (None, Some(re)) => Matcher::Regex(Regex::new(&re)
+                    .map_err(|e| format!("Invalid SINK_RULES regex `{}`: {}", re, e))?),
+                _ => return Err("Each SINK_RULES entry needs exactly one of `name` or `regex`".into()),
+            };
+            rules.push(SinkRule { matcher, step: r.step, max: r.max, default: r.default });
+        }
+        Ok(Self(rules))
+    }
+
+    pub fn for_sink(&self, sink_name: &str) -> Option<&SinkRule> {
+        self.0.iter().find(|r| r.matches(sink_name))
+    }
+}
+
+/// Raw volume for a percentage of the normal (100%) level.
+pub fn volume_from_percent(pct: u16) -> Volume {
+    Volume((Volume::NORMAL.0 as u64 * pct as u64 / 100) as u32)
+}
+
+/// Lower every channel of `cv` to at most `max_pct` percent.
+pub fn cap_volume(cv: &mut ChannelVolumes, max_pct: u16) {
+    let limit = volume_from_percent(max_pct);
+    for v in cv.get_mut() {
+        if *v > limit {
+            *v = limit;
+        }
+    }
+}
+
+/// `current` with every channel set to `pct` percent.
+pub fn volume_at_percent(current: &ChannelVolumes, pct: u16) -> ChannelVolumes {
+    let mut out = *current;
+    for v in out.get_mut() {
+        *v = volume_from_percent(pct);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = SinkRules::parse(r#"[
+            {"name": "alsa_output.iem", "step": 1, "max": 40},
+            {"regex": "^alsa_output\\.", "step": 5}
+        ]"#).unwrap();
+        assert_eq!(rules.for_sink("alsa_output.iem").unwrap().max, Some(40));
+        assert_eq!(rules.for_sink("alsa_output.pci").unwrap().step, Some(5));
+        assert!(rules.for_sink("bluez_output.x").is_none());
+    }
+
+    #[test]
+    fn parse_rejects_bad_rules() {
+        assert!(SinkRules::parse("").unwrap().for_sink("x").is_none());
+        assert!(SinkRules::parse(r#"[{"step": 1}]"#).is_err());
+        assert!(SinkRules::parse(r#"[{"name": "a", "regex": "b"}]"#).is_err());
+        assert!(SinkRules::parse(r#"[{"regex": "("}]"#).is_err());
+        assert!(SinkRules::parse(r#"[{"name": "a", "volume": 3}]"#).is_err());
+    }
+
+    #[test]
+    fn cap_volume_limits_each_channel() {
+        let mut cv = ChannelVolumes::default();
+        cv.set_len(2);
+        cv.get_mut()[0] = volume_from_percent(80);
+        cv.get_mut()[1] = volume_from_percent(30);
+        cap_volume(&mut cv, 50);
+        assert_eq!(cv.get(), &[volume_from_percent(50), volume_from_percent(30)]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::os::unix::io::RawFd;
+use std::sync::mpsc;
+use std::thread;
+
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::dbus_interface;
+
+use log::warn;
+
+use super::{write_command, MAX_SET_VOLUME};
+
+/// Well-known session-bus name, object path and interface of the service.
+pub const BUS_NAME: &str = "org.i3blocks.VolumePw";
+const OBJECT_PATH: &str = "/org/i3blocks/VolumePw";
+
+/// What the service's properties report, as of the last render.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub volume: u16,
+    pub muted: bool,
+    pub sink_name: String,
+    /// Lowest battery level of the displayed device, if it has one.
+    pub battery: Option<u8>,
+}
+
+/// `org.i3blocks.VolumePw`: methods become text commands on the event loop's
+/// command pipe, properties mirror the displayed sink.
+struct VolumePw {
+    commands: RawFd,
+    snapshot: Snapshot,
+}
+
+#[dbus_interface(name = "org.i3blocks.VolumePw")]
+impl VolumePw {
+    fn volume_up(&self) {
+        write_command(self.commands, "up");
+    }
+
+    fn volume_down(&self) {
+        write_command(self.commands, "down");
+    }
+
+    /// Set the volume of the displayed sink, in percent (at most 150).
+    fn set_volume(&self, percent: u16) -> zbus::fdo::Result<()> {
+        if percent > MAX_SET_VOLUME {
+            return Err(zbus::fdo::Error::InvalidArgs(format!("volume {}% is above {}%", percent, MAX_SET_VOLUME)));
+        }
+        write_command(self.commands, &format!("volume {}", percent));
+        Ok(())
+    }
+
+    fn toggle_mute(&self) {
+        write_command(self.commands, "mute");
+    }
+
+    /// Make the next sink the default.
+    fn next_sink(&self) {
+        write_command(self.commands, "next-sink");
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> u16 {
+        self.snapshot.volume
+    }
+
+    #[dbus_interface(property)]
+    fn muted(&self) -> bool {
+        self.snapshot.muted
+    }
+
+    #[dbus_interface(property)]
+    fn sink_name(&self) -> String {
+        self.snapshot.sink_name.clone()
+    }
+
+    /// Battery percentage of the displayed device, or -1 without one.
+    #[dbus_interface(property)]
+    fn battery(&self) -> i32 {
+        self.snapshot.battery.map_or(-1, i32::from)
+    }
+}
+
+/// Register [`BUS_NAME`] on the session bus. Send the returned channel a
+/// snapshot after every render; properties that changed are updated and
+/// announced with `PropertiesChanged` from the service's own thread.
+pub fn spawn(commands: RawFd) -> zbus::Result<mpsc::Sender<Snapshot>> {
+    let conn = ConnectionBuilder::session()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, VolumePw { commands, snapshot: Snapshot::default() })?
+        .build()?;
+    let (tx, rx) = mpsc::channel::<Snapshot>();
+    thread::Builder::new().name("dbus-service".to_string()).spawn(move || {
+        while let Ok(mut snapshot) = rx.recv() {
+            while let Ok(newer) = rx.try_recv() {
+                snapshot = newer;
+            }
+            if let Err(e) = publish(&conn, snapshot) {
+                warn!("Failed to update {} properties: {}", BUS_NAME, e);
+            }
+        }
+    }).map_err(|e| zbus::Error::Failure(e.to_string()))?;
+    Ok(tx)
+}
+
+fn publish(conn: &Connection, snapshot: Snapshot) -> zbus::Result<()> {
+    let iface_ref = conn.object_server().interface::<_, VolumePw>(OBJECT_PATH)?;
+    let mut iface = iface_ref.get_mut();
+    let old = std::mem::replace(&mut iface.snapshot, snapshot);
+    let new = &iface.snapshot;
+    let ctxt = iface_ref.signal_context();
+    zbus::block_on(async {
+        if old.volume != new.volume {
+            iface.volume_changed(ctxt).await?;
+        }
+        if old.muted != new.muted {
+            iface.muted_changed(ctxt).await?;
+        }
+        if old.sink_name != new.sink_name {
+            iface.sink_name_changed(ctxt).await?;
+        }
+        if old.battery != new.battery {
+            iface.battery_changed(ctxt).await?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use zbus::blocking::{fdo::PropertiesProxy, Connection, Proxy};
+use zbus::names::InterfaceName;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+use super::BatteryTarget;
+use super::battery::{Battery, Level, Part};
+
+/// UPower `Type` values of devices that are never an audio device's battery.
+const TYPE_LINE_POWER: u32 = 1;
+const TYPE_BATTERY: u32 = 2;
+/// UPower `Type` values of audio devices (headset, speakers, headphones, other audio).
+const AUDIO_TYPES: [u32; 4] = [17, 18, 19, 21];
+/// UPower `State` value for a charging battery.
+const STATE_CHARGING: u32 = 1;
+
+/// The parts of an `org.freedesktop.UPower.Device` used for matching.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UPowerDevice {
+    pub native_path: String,
+    pub model: String,
+    pub serial: String,
+    pub kind: u32,
+    pub percentage: f64,
+    pub state: u32,
+}
+
+impl UPowerDevice {
+    fn from_props(props: &HashMap<String, OwnedValue>) -> Self {
+        let string = |k: &str| props.get(k).and_then(|v| v.downcast_ref::<str>()).unwrap_or_default().to_string();
+        let uint = |k: &str| props.get(k).and_then(|v| v.downcast_ref::<u32>()).copied().unwrap_or_default();
+        Self {
+            native_path: string("NativePath"),
+            model: string("Model"),
+            serial: string("Serial"),
+            kind: uint("Type"),
+            percentage: props.get("Percentage").and_then(|v| v.downcast_ref::<f64>()).copied().unwrap_or_default(),
+            state: uint("State"),
+        }
+    }
+
+    /// This device's level; earbuds show up as one device per bud/case, named
+    /// e.g. `Galaxy Buds2 Pro (Left)`.
+    fn level(&self) -> Level {
+        Level {
+            part: Part::from_name(&self.model),
+            percentage: self.percentage.round().clamp(0.0, 100.0) as u8,
+            charging: self.state == STATE_CHARGING,
+        }
+    }
+
+    /// Whether this device is the one behind `target`: by MAC (Bluetooth), by
+    /// serial number, or, for audio-type devices, by model name.
+    fn matches(&self, target: &BatteryTarget) -> bool {
+        if matches!(self.kind, TYPE_LINE_POWER | TYPE_BATTERY) {
+            return false;
+        }
+        if let Some(mac) = &target.mac {
+            let dev_mac = mac.replace(':', "_");
+            if self.serial.eq_ignore_ascii_case(mac) || self.native_path.to_uppercase().contains(&dev_mac) {
+                return true;
+            }
+        }
+        let serial = normalize(&self.serial);
+        if serial.len() >= 4 && target.serial.as_deref().is_some_and(|s| normalize(s).contains(&serial)) {
+            return true;
+        }
+        let model = normalize(&self.model);
+        AUDIO_TYPES.contains(&self.kind)
+            && !model.is_empty()
+            && target.model.as_deref().map(normalize).is_some_and(|m| !m.is_empty() && (m.contains(&model) || model.contains(&m)))
+    }
+}
+
+/// Lower-case alphanumerics only, so `G533 Gaming-Headset` matches `g533_gaming_headset`.
+fn normalize(s: &str) -> String {
+    s.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// All devices UPower knows about.
+pub fn devices(conn: &Connection) -> zbus::Result<Vec<UPowerDevice>> {
+    let upower = Proxy::new(conn, "org.freedesktop.UPower", "/org/freedesktop/UPower", "org.freedesktop.UPower")?;
+    let paths: Vec<OwnedObjectPath> = upower.call("EnumerateDevices", &())?;
+    let iface = InterfaceName::from_static_str("org.freedesktop.UPower.Device")?;
+    let mut devices = Vec::with_capacity(paths.len());
+    for path in paths {
+        let props = PropertiesProxy::builder(conn)
+            .destination("org.freedesktop.UPower")?
+            .path(path)?
+            .build()?;
+        if let Ok(all) = props.get_all(iface.clone()) {
+            devices.push(UPowerDevice::from_props(&all));
+        }
+    }
+    Ok(devices)
+}
+
+/// Find the batteries of `target` among UPower's devices (best-effort).
+pub fn find_battery(conn: &Connection, target: &BatteryTarget) -> Option<Battery> {
+    let battery: Battery = devices(conn).ok()?.iter().filter(|d| d.matches(target)).map(UPowerDevice::level).collect();
+    Some(battery).filter(|b| !b.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn target(mac: Option<&str>, serial: Option<&str>, model: Option<&str>) -> BatteryTarget {
+        BatteryTarget {
+            key: "k".into(),
+            mac: mac.map(str::to_string),
+            serial: serial.map(str::to_string),
+            model: model.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn match_by_mac_serial_and_model() {
+        let bt = UPowerDevice { native_path: "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF".into(), kind: 17, ..Default::default() };
+        assert!(bt.matches(&target(Some("AA:BB:CC:DD:EE:FF"), None, None)));
+        assert!(!bt.matches(&target(Some("11:22:33:44:55:66"), None, None)));
+
+        let dongle = UPowerDevice { model: "G533 Gaming Headset".into(), serial: "1a2b3c4d".into(), kind: 17, ..Default::default() };
+        assert!(dongle.matches(&target(None, Some("Logitech_G533_1A2B3C4D"), None)));
+        assert!(dongle.matches(&target(None, None, Some("G533 Gaming-Headset"))));
+        assert!(!dongle.matches(&target(None, None, Some("Creative USB Headset"))));
+    }
+
+    #[test]
+    fn model_match_needs_audio_type() {
+        let mouse = UPowerDevice { model: "G533 Gaming Headset".into(), kind: 5, ..Default::default() };
+        assert!(!mouse.matches(&target(None, None, Some("G533 Gaming Headset"))));
+        let laptop = UPowerDevice { serial: "1a2b3c4d".into(), kind: TYPE_BATTERY, ..Default::default() };
+        assert!(!laptop.matches(&target(None, Some("1a2b3c4d"), None)));
+    }
+
+    #[test]
+    fn battery_from_props() {
+        let props: HashMap<String, OwnedValue> = [
+            ("Percentage".to_string(), OwnedValue::from(64.6f64)),
+            ("State".to_string(), OwnedValue::from(STATE_CHARGING)),
+            ("Type".to_string(), OwnedValue::from(17u32)),
+        ].into_iter().collect();
+        let dev = UPowerDevice::from_props(&props);
+        assert_eq!(dev.kind, 17);
+        assert_eq!(dev.level(), Level { part: Part::Main, percentage: 65, charging: true });
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+/// First and longest wait before respawning a wob that died or failed to start.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// wob ≥0.15 styles (`[style.<name>]` in `wob.ini`) used with `WOB_STYLES`.
+const STYLE_MUTED: &str = "muted";
+const STYLE_OVERAMPLIFIED: &str = "overamplified";
+
+/// Where volume values go.
+enum Target {
+    /// Our own wob child (a shell command line), respawned when it dies.
+    Spawn { command: String, child: Option<(Child, Instant)> },
+    /// An existing wob FIFO or Unix socket, opened for every value.
+    Path(PathBuf),
+}
+
+/// Volume overlay through wob.
+pub struct Wob {
+    target: Target,
+    styles: bool,
+    /// Consecutive failures, and when the next attempt is allowed.
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Wob {
+    /// Spawn `command` (e.g. `wob --config ~/.config/wob/volume.ini`) through
+    /// `sh` and feed its stdin.
+    pub fn spawn(command: &str, styles: bool) -> Self {
+        let command = command.trim().to_string();
+        let mut wob = Self { target: Target::Spawn { command, child: None }, styles, failures: 0, retry_at: None };
+        wob.ensure_child();
+        wob
+    }
+
+    /// Write to an existing wob FIFO or socket.
+    pub fn path(path: PathBuf, styles: bool) -> Self {
+        Self { target: Target::Path(path), styles, failures: 0, retry_at: None }
+    }
+
+    /// Show `volume`. A wob that ran for a while and then died is respawned
+    /// right away; one that dies soon after starting, or any other failure,
+    /// backs off (doubling up to a minute) before wob is respawned or the
+    /// FIFO/socket is tried again; values in between are dropped.
+    pub fn show(&mut self, volume: u16, muted: bool) {
+        let now = Instant::now();
+        if self.retry_at.is_some_and(|t| now < t) {
+            return;
+        }
+        let line = wob_line(volume, muted, self.styles);
+        let res = match &self.target {
+            Target::Spawn { .. } => self.write_child(&line),
+            Target::Path(path) => write_path(path, &line).map(|()| self.failures = 0),
+        };
+        match res {
+            Ok(()) => self.retry_at = None,
+            Err(e) => {
+                let wait = backoff(self.failures);
+                if self.failures == 0 {
+                    warn!("Error writing to wob, retrying with backoff: {}", e);
+                } else {
+                    info!("Error writing to wob again, retrying in {:?}: {}", wait, e);
+                }
+                self.retry_at = Some(now + wait);
+                self.failures += 1;
+                self.reap();
+            }
+        }
+    }
+
+    fn write_child(&mut self, line: &str) -> io::Result<()> {
+        if let Target::Spawn { child: Some((child, started)), .. } = &mut self.target {
+            if let Some(status) = child.try_wait()? {
+                let uptime = started.elapsed();
+                self.reap();
+                // Only a wob that stayed up is worth restarting at once; one that
+                // keeps crashing (e.g. a broken `WOB_COMMAND`) backs off.
+                if uptime < MAX_BACKOFF {
+                    return Err(io::Error::other(format!("wob exited ({}) after {:?}", status, uptime)));
+                }
+            }
+        }
+        self.ensure_child();
+        let Target::Spawn { child: Some((child, started)), .. } = &mut self.target else {
+            return Err(io::Error::other("wob is not running"));
+        };
+        let stdin = child.stdin.as_mut().ok_or_else(|| io::Error::other("wob has no stdin"))?;
+        writeln!(stdin, "{}", line)?;
+        stdin.flush()?;
+        // Only a wob that stays up a while resets the backoff.
+        if started.elapsed() >= MAX_BACKOFF {
+            self.failures = 0;
+        }
+        Ok(())
+    }
+
+    fn ensure_child(&mut self) {
+        if let Target::Spawn { command, child: child @ None } = &mut self.target {
+            if command.is_empty() {
+                return;
+            }
+            // `exec` so the child is wob itself, not a shell waiting for it.
+            let shell = format!("exec {}", command);
+            match Command::new("sh").arg("-c").arg(shell).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() {
+                Ok(c) => *child = Some((c, Instant::now())),
+                Err(e) => warn!("Failed to spawn wob: {}", e),
+            }
+        }
+    }
+
+    /// Close the child's stdin (wob exits on EOF), then make sure it's gone and reaped.
+    fn reap(&mut self) {
+        if let Target::Spawn { child, .. } = &mut self.target {
+            if let Some((mut c, _)) = child.take() {
+                drop(c.stdin.take());
+                let _ = c.kill();
+                let _ = c.wait();
+            }
+        }
+    }
+}
+
+impl Drop for Wob {
+    fn drop(&mut self) {
+        self.reap();
+    }
+}
+
+/// Write one line to a wob socket or FIFO. A FIFO without a reader fails
+/// right away instead of blocking the event loop.
+fn write_path(path: &Path, line: &str) -> io::Result<()> {
+    let line = format!("{}\n", line);
+    if std::fs::metadata(path)?.file_type().is_socket() {
+        UnixStream::connect(path)?.write_all(line.as_bytes())
+    } else {
+        OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path)?.write_all(line.as_bytes())
+    }
+}
+
+/// The line wob reads: the volume, plus a style name for muted or over-100%
+/// volume with `styles` (without, a muted sink shows as 0).
+fn wob_line(volume: u16, muted: bool, styles: bool) -> String {
+    match (styles, muted) {
+        (false, true) => "0".to_string(),
+        (false, false) => volume.to_string(),
+        (true, true) => format!("{} {}", volume, STYLE_MUTED),
+        (true, false) if volume > 100 => format!("{} {}", volume, STYLE_OVERAMPLIFIED),
+        (true, false) => volume.to_string(),
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF.saturating_mul(1 << failures.min(6)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lines_with_and_without_styles() {
+        assert_eq!(wob_line(40, false, false), "40");
+        assert_eq!(wob_line(40, true, false), "0");
+        assert_eq!(wob_line(40, true, true), "40 muted");
+        assert_eq!(wob_line(120, false, true), "120 overamplified");
+        assert_eq!(wob_line(100, false, true), "100");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+    }
+
+    fn kill_child(wob: &mut Wob) -> u32 {
+        let Target::Spawn { child: Some((c, _)), .. } = &mut wob.target else { panic!("no child") };
+        let _ = c.kill();
+        c.wait().unwrap();
+        c.id()
+    }
+
+    #[test]
+    fn long_running_dead_child_respawns_at_once() {
+        let mut wob = Wob::spawn("cat > /dev/null", false);
+        if let Target::Spawn { child: Some((_, started)), .. } = &mut wob.target {
+            *started -= MAX_BACKOFF;
+        }
+        let old = kill_child(&mut wob);
+        wob.show(50, false);
+        assert_eq!((wob.failures, wob.retry_at), (0, None));
+        let Target::Spawn { child: Some((c, _)), .. } = &wob.target else { panic!("not respawned") };
+        assert_ne!(c.id(), old);
+    }
+
+    #[test]
+    fn crashing_child_backs_off() {
+        let mut wob = Wob::spawn("exit 1", false);
+        kill_child(&mut wob);
+        for _ in 0..5 {
+            wob.show(50, false);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        // One failure, and no respawn until the backoff is over.
+        assert_eq!(wob.failures, 1);
+        assert!(matches!(wob.target, Target::Spawn { child: None, .. }));
+        assert!(wob.retry_at.is_some_and(|t| t > Instant::now()));
+    }
+
+    #[test]
+    fn fifo_without_reader_fails_fast() {
+        let path = std::env::temp_dir().join(format!("i3blocks-volume-pw-wob-{}", std::process::id()));
+        let c = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o600) }, 0);
+        let mut wob = Wob::path(path.clone(), false);
+        wob.show(50, false);
+        assert_eq!(wob.failures, 1);
+        assert!(wob.retry_at.is_some());
+        let _ = std::fs::remove_file(path);
+    }
+}